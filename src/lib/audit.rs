@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use super::file_entry::FileEntry;
+use super::fs::AbstractFs;
+use super::path_serde;
+use super::reconcile::MetadataChange;
+use super::{ErrorKind, Result};
+
+// what happened to a file since the index row for it was written
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Change {
+    Deleted,
+    Modified,
+    PermissionDenied,
+    // same device, inode, size and mtime, found under another path
+    Moved(PathBuf),
+    // stat failed for some other reason
+    Unreadable(String),
+}
+
+impl Change {
+    // compares an index row with what stat() says now, None if the row is still good
+    pub fn classify(old_entry: &FileEntry, on_disk: &Result<FileEntry>) -> Option<Self> {
+        match on_disk {
+            Ok(on_disk) if old_entry.eq_except_hash(on_disk) => None,
+            Ok(_) => Some(Change::Modified),
+            Err(e) => match e.kind() {
+                ErrorKind::NotFound => Some(Change::Deleted),
+                ErrorKind::PermissionDenied => Some(Change::PermissionDenied),
+                _ => Some(Change::Unreadable(e.to_string())),
+            },
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Change::Deleted => "deleted",
+            Change::Modified => "modified",
+            Change::PermissionDenied => "permission-denied",
+            Change::Moved(_) => "moved",
+            Change::Unreadable(_) => "unreadable",
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Moved(to) => write!(f, "moved to {}", to.display()),
+            Change::Unreadable(e) => write!(f, "unreadable: {}", e),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StaleEntry {
+    // the row as it was in the index
+    pub entry: FileEntry,
+    pub change: Change,
+}
+
+// the index rows that were dropped when loading, because they no longer match the disk
+#[derive(Debug, Clone, Default)]
+pub struct StaleEntries {
+    entries: Vec<StaleEntry>,
+    // deleted rows by file_id(), so a new path can be matched up with the one it was moved from
+    deleted_by_inode: HashMap<(u64, u64), Vec<usize>>,
+}
+
+impl StaleEntries {
+    pub fn push(&mut self, entry: FileEntry, change: Change) {
+        if change == Change::Deleted {
+            self.deleted_by_inode.entry(entry.file_id()).or_default().push(self.entries.len());
+        }
+        self.entries.push(StaleEntry { entry, change });
+    }
+
+    // called for every path that wasn't in the index; if it is a deleted file under a new name,
+    // the deleted row becomes a move
+    pub fn note_new_path(&mut self, new_entry: &FileEntry) -> Option<&StaleEntry> {
+        let idxs = self.deleted_by_inode.get_mut(&new_entry.file_id())?;
+        let entries = &self.entries;
+        let pos = idxs.iter().position(|&i| {
+            let old = &entries[i].entry;
+            (old.stat_size, old.stat_modified) == (new_entry.stat_size, new_entry.stat_modified)
+        })?;
+        let idx = idxs.swap_remove(pos);
+        self.entries[idx].change = Change::Moved(new_entry.relative_path.clone());
+        Some(&self.entries[idx])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&StaleEntry> {
+        self.entries.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // one line for the run summary, like "3 stale index entries: 2 deleted, 1 moved"
+    pub fn summary(&self) -> String {
+        let mut counts: Vec<(&'static str, usize)> = vec![];
+        for stale in &self.entries {
+            match counts.iter_mut().find(|(name, _)| *name == stale.change.name()) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((stale.change.name(), 1)),
+            }
+        }
+        counts.sort();
+        let counts: Vec<String> = counts.iter()
+            .map(|(name, count)| format!("{} {}", count, name))
+            .collect();
+        format!("{} stale index entries: {}", self.entries.len(), counts.join(", "))
+    }
+
+    // appends one csv row per stale entry to the changes log, so churn between runs can be
+    // audited later. There is no header, since every run adds to the same file.
+    pub fn append_to_log<Fs: AbstractFs, P: AsRef<Path>, Q: AsRef<Path>>(&self, fs: &mut Fs, log_path: P, base_path: Q, time: SystemTime) -> Result<()> {
+        let records = self.entries.iter().map(|stale| LogRecord {
+            time,
+            base_path: base_path.as_ref(),
+            relative_path: &stale.entry.relative_path,
+            change: stale.change.name(),
+            detail: match &stale.change {
+                Change::Moved(to) => path_serde::encode(to).into_owned(),
+                Change::Unreadable(e) => e.clone(),
+                _ => String::new(),
+            },
+        });
+        append_records(fs, log_path, records)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Reconciled {
+    // the path that was just linked, the change applies to every path of its inode
+    pub relative_path: PathBuf,
+    pub change: MetadataChange,
+}
+
+// what the metadata policy changed on linked files during a run, see reconcile::Policy
+#[derive(Debug, Clone, Default)]
+pub struct Reconciliations {
+    changes: Vec<Reconciled>,
+}
+
+impl Reconciliations {
+    pub fn push(&mut self, relative_path: &Path, change: MetadataChange) {
+        self.changes.push(Reconciled { relative_path: relative_path.to_owned(), change });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&Reconciled> {
+        self.changes.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn summary(&self) -> String {
+        let count = |name| self.changes.iter().filter(|c| c.change.name() == name).count();
+        format!("changed the mtime of {}, the mode of {} and the owner of {} linked files",
+                count("mtime"), count("mode"), count("owner"))
+    }
+
+    // same log as StaleEntries, with "reconciled-mtime", "reconciled-mode" and "reconciled-owner" rows
+    pub fn append_to_log<Fs: AbstractFs, P: AsRef<Path>, Q: AsRef<Path>>(&self, fs: &mut Fs, log_path: P, base_path: Q, time: SystemTime) -> Result<()> {
+        let records = self.changes.iter().map(|reconciled| LogRecord {
+            time,
+            base_path: base_path.as_ref(),
+            relative_path: &reconciled.relative_path,
+            change: match reconciled.change {
+                MetadataChange::Mtime(..) => "reconciled-mtime",
+                MetadataChange::Mode(..) => "reconciled-mode",
+                MetadataChange::Owner(..) => "reconciled-owner",
+            },
+            detail: reconciled.change.to_string(),
+        });
+        append_records(fs, log_path, records)
+    }
+}
+
+fn append_records<'a, Fs: AbstractFs, P: AsRef<Path>>(fs: &mut Fs, log_path: P, records: impl Iterator<Item=LogRecord<'a>>) -> Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(vec![]);
+    for record in records {
+        wtr.serialize(record)?;
+    }
+    let buf = wtr.into_inner().map_err(|e| e.to_string())?;
+    fs.append_to_file(log_path, &buf)
+}
+
+#[derive(Serialize)]
+struct LogRecord<'a> {
+    #[serde(with = "humantime_serde")]
+    time: SystemTime,
+    #[serde(with = "super::path_serde")]
+    base_path: &'a Path,
+    #[serde(with = "super::path_serde")]
+    relative_path: &'a Path,
+    change: &'static str,
+    detail: String,
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::time::SystemTime;
+
+    use crate::lib::audit::{Change, StaleEntries};
+    use crate::lib::file_entry::FileEntry;
+    use crate::lib::fs::{AbstractFs, TestFs};
+
+    #[test]
+    fn test_classify() {
+        let mut test_fs = TestFs::default();
+        test_fs.set_cwd("/somefolder/");
+        let f1 = test_fs.new_file_entry("/somefolder/test1", "asdf");
+        let f2 = test_fs.new_file_entry("/somefolder/test2", "asdf");
+        let f3 = test_fs.new_file_entry("/somefolder/test3", "asdf");
+        let f4 = test_fs.new_file_entry("/somefolder/test4", "asdf");
+        test_fs.remove_file("/somefolder/test2").unwrap();
+        test_fs.add_text_file("/somefolder/test3", "qwerty");
+        test_fs.unreadable.insert("/somefolder/test4".to_owned());
+
+        let on_disk = |e: &FileEntry| FileEntry::new(&test_fs, "/somefolder/", e.absolute_path("/somefolder/"));
+        assert_eq!(Change::classify(&f1, &on_disk(&f1)), None);
+        assert_eq!(Change::classify(&f2, &on_disk(&f2)), Some(Change::Deleted));
+        assert_eq!(Change::classify(&f3, &on_disk(&f3)), Some(Change::Modified));
+        assert_eq!(Change::classify(&f4, &on_disk(&f4)), Some(Change::PermissionDenied));
+    }
+
+    #[test]
+    fn test_moved_and_log() {
+        let mut test_fs = TestFs::default();
+        test_fs.set_cwd("/somefolder/");
+        let f1 = test_fs.new_file_entry("/somefolder/test1", "asdf");
+        let f2 = test_fs.new_file_entry("/somefolder/test2", "qwer");
+        test_fs.rename("/somefolder/test1", "/somefolder/test3").unwrap();
+        test_fs.remove_file("/somefolder/test2").unwrap();
+
+        let mut stale = StaleEntries::default();
+        stale.push(f1, Change::Deleted);
+        stale.push(f2, Change::Deleted);
+        let f3 = FileEntry::new(&test_fs, "/somefolder/", "/somefolder/test3").unwrap();
+        let moved = stale.note_new_path(&f3).unwrap();
+        assert_eq!(moved.entry.relative_path, Path::new("test1"));
+        assert_eq!(moved.change, Change::Moved(PathBuf::from("test3")));
+        // it can only be moved once
+        assert!(stale.note_new_path(&f3).is_none());
+        assert_eq!(stale.summary(), "2 stale index entries: 1 deleted, 1 moved");
+
+        let time = SystemTime::UNIX_EPOCH;
+        stale.append_to_log(&mut test_fs, "/changes.csv", "/somefolder/", time).unwrap();
+        stale.append_to_log(&mut test_fs, "/changes.csv", "/somefolder/", time).unwrap();
+        let log = std::str::from_utf8(test_fs.get_file_data("/changes.csv").unwrap()).unwrap();
+        assert_eq!(log, "1970-01-01T00:00:00Z,/somefolder/,test1,moved,test3
+1970-01-01T00:00:00Z,/somefolder/,test2,deleted,
+1970-01-01T00:00:00Z,/somefolder/,test1,moved,test3
+1970-01-01T00:00:00Z,/somefolder/,test2,deleted,
+");
+    }
+
+    #[test]
+    fn test_log_moved_to_non_utf8_name() {
+        let mut test_fs = TestFs::default();
+        test_fs.set_cwd("/somefolder/");
+        let f1 = test_fs.new_file_entry("/somefolder/cafe", "asdf");
+        let moved_to = Path::new(OsStr::from_bytes(b"/somefolder/caf\xe9"));
+        test_fs.rename("/somefolder/cafe", moved_to).unwrap();
+
+        let mut stale = StaleEntries::default();
+        stale.push(f1, Change::Deleted);
+        stale.note_new_path(&FileEntry::new(&test_fs, "/somefolder/", moved_to).unwrap()).unwrap();
+        stale.append_to_log(&mut test_fs, "/changes.csv", "/somefolder/", SystemTime::UNIX_EPOCH).unwrap();
+        let log = std::str::from_utf8(test_fs.get_file_data("/changes.csv").unwrap()).unwrap();
+        assert_eq!(log, "1970-01-01T00:00:00Z,/somefolder/,cafe,moved,base64:Y2Fm6Q==\n");
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use super::file_entry::FileEntry;
+use super::files_index::IndexHeader;
+use super::{Error, Result};
+
+// a compact alternative to the csv index for trees with millions of files. The file starts with
+// MAGIC, which no csv index can start with, followed by the fields of the IndexHeader and then
+// the rows grouped by directory:
+//
+//   varint directory count
+//   per directory: varint length of the prefix it shares with the previous directory, the rest
+//                  of its path, varint file count
+//   per file:      file name, flags byte, [16 byte fast hash], varint size, modified, created,
+//                  varint inode, varint device, changed, [digest]
+//
+// Integers are LEB128 varints, byte strings are a varint length followed by the bytes, and times
+// are varint seconds and nanoseconds since 1970. Directory paths keep their trailing slash, so
+// joining them with the file name gives back the exact path, absolute or not.
+pub const MAGIC: &[u8] = b"\0HLDDIX\n";
+
+const HAS_FAST_HASH: u8 = 1;
+const KEEP_SEPARATE: u8 = 2;
+const HAS_DIGEST: u8 = 4;
+
+pub fn write<'a, W: Write, I: IntoIterator<Item = &'a FileEntry>>(writer: &mut W, header: &IndexHeader, entries: I) -> Result<()> {
+    let mut by_dir: BTreeMap<&[u8], Vec<(&[u8], &FileEntry)>> = BTreeMap::new();
+    for entry in entries {
+        let (dir, name) = split_path(entry.relative_path.as_os_str().as_bytes());
+        by_dir.entry(dir).or_default().push((name, entry));
+    }
+
+    writer.write_all(MAGIC)?;
+    write_varint(writer, header.version as u64)?;
+    write_bytes(writer, header.tool_version.as_bytes())?;
+    write_bytes(writer, header.fast_hash.as_bytes())?;
+    write_bytes(writer, header.base_path.as_os_str().as_bytes())?;
+    write_time(writer, header.created)?;
+
+    write_varint(writer, by_dir.len() as u64)?;
+    let mut previous_dir: &[u8] = b"";
+    for (dir, files) in by_dir {
+        let shared = dir.iter().zip(previous_dir).take_while(|(a, b)| a == b).count();
+        write_varint(writer, shared as u64)?;
+        write_bytes(writer, &dir[shared..])?;
+        write_varint(writer, files.len() as u64)?;
+        for (name, entry) in files {
+            write_entry(writer, name, entry)?;
+        }
+        previous_dir = dir;
+    }
+    Ok(())
+}
+
+// the caller has already read and checked MAGIC. The format name in the header is implied.
+pub fn read<R: Read>(reader: &mut R, format_name: &str) -> Result<(IndexHeader, Vec<FileEntry>)> {
+    let header = IndexHeader {
+        format: format_name.to_owned(),
+        version: u32::try_from(read_varint(reader)?).map_err(|_| Error::from("the index format version is out of range"))?,
+        tool_version: read_string(reader)?,
+        fast_hash: read_string(reader)?,
+        base_path: PathBuf::from(OsStr::from_bytes(&read_bytes(reader)?)),
+        created: read_time(reader)?,
+    };
+
+    let mut entries = vec![];
+    let mut dir: Vec<u8> = vec![];
+    for _ in 0..read_varint(reader)? {
+        let shared = read_varint(reader)? as usize;
+        if shared > dir.len() {
+            return Err(Error::from("the index shares more of a directory than the previous one has"));
+        }
+        dir.truncate(shared);
+        dir.extend(read_bytes(reader)?);
+        for _ in 0..read_varint(reader)? {
+            entries.push(read_entry(reader, &dir)?);
+        }
+    }
+    let mut rest = [0u8; 1];
+    if reader.read(&mut rest)? != 0 {
+        return Err(Error::from("the index has data after the last row"));
+    }
+    Ok((header, entries))
+}
+
+// splits after the last slash, so that dir + name is the path again
+fn split_path(path: &[u8]) -> (&[u8], &[u8]) {
+    match path.iter().rposition(|&b| b == b'/') {
+        Some(i) => path.split_at(i + 1),
+        None => (b"", path),
+    }
+}
+
+fn write_entry<W: Write>(writer: &mut W, name: &[u8], entry: &FileEntry) -> Result<()> {
+    let mut flags = 0;
+    if entry.fast_hash.is_some() {
+        flags |= HAS_FAST_HASH;
+    }
+    if entry.keep_separate {
+        flags |= KEEP_SEPARATE;
+    }
+    if entry.digest.is_some() {
+        flags |= HAS_DIGEST;
+    }
+    write_bytes(writer, name)?;
+    writer.write_all(&[flags])?;
+    if let Some(hash) = entry.fast_hash {
+        writer.write_all(&hash.to_le_bytes())?;
+    }
+    write_varint(writer, entry.stat_size)?;
+    write_time(writer, entry.stat_modified)?;
+    write_time(writer, entry.stat_created)?;
+    write_varint(writer, entry.stat_inode)?;
+    write_varint(writer, entry.stat_device)?;
+    write_time(writer, entry.stat_changed)?;
+    if let Some(digest) = &entry.digest {
+        write_bytes(writer, digest.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_entry<R: Read>(reader: &mut R, dir: &[u8]) -> Result<FileEntry> {
+    let mut path = dir.to_vec();
+    path.extend(read_bytes(reader)?);
+    let mut flags = [0u8; 1];
+    read_exact(reader, &mut flags)?;
+    let flags = flags[0];
+    if flags & !(HAS_FAST_HASH | KEEP_SEPARATE | HAS_DIGEST) != 0 {
+        return Err(Error::from(format!("unknown flags {:#x} in the index", flags)));
+    }
+    let fast_hash = if flags & HAS_FAST_HASH != 0 {
+        let mut hash = [0u8; 16];
+        read_exact(reader, &mut hash)?;
+        Some(u128::from_le_bytes(hash))
+    } else {
+        None
+    };
+    Ok(FileEntry {
+        relative_path: PathBuf::from(OsStr::from_bytes(&path)),
+        fast_hash,
+        stat_size: read_varint(reader)?,
+        stat_modified: read_time(reader)?,
+        stat_created: read_time(reader)?,
+        stat_inode: read_varint(reader)?,
+        stat_device: read_varint(reader)?,
+        stat_changed: read_time(reader)?,
+        keep_separate: flags & KEEP_SEPARATE != 0,
+        digest: if flags & HAS_DIGEST != 0 { Some(read_string(reader)?) } else { None },
+    })
+}
+
+fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            writer.write_all(&[byte])?;
+            return Ok(());
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8; 1];
+        read_exact(reader, &mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::from("a number in the index is too long"))
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    write_varint(writer, bytes.len() as u64)?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let len = read_varint(reader)?;
+    // a corrupt length shouldn't make us allocate it all up front
+    let mut bytes = vec![];
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(Error::from("the index is cut off"));
+    }
+    Ok(bytes)
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Result<String> {
+    String::from_utf8(read_bytes(reader)?).map_err(|e| Error::from(format!("bad text in the index: {}", e)))
+}
+
+fn write_time<W: Write>(writer: &mut W, time: SystemTime) -> Result<()> {
+    let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| Error::from("can't store a time before 1970 in a binary index"))?;
+    write_varint(writer, since_epoch.as_secs())?;
+    write_varint(writer, since_epoch.subsec_nanos() as u64)
+}
+
+fn read_time<R: Read>(reader: &mut R) -> Result<SystemTime> {
+    let secs = read_varint(reader)?;
+    let nanos = read_varint(reader)?;
+    if nanos >= 1_000_000_000 {
+        return Err(Error::from("a time in the index has too many nanoseconds"));
+    }
+    SystemTime::UNIX_EPOCH.checked_add(Duration::new(secs, nanos as u32))
+        .ok_or_else(|| Error::from("a time in the index is out of range"))
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => Error::from("the index is cut off"),
+        _ => e.into(),
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use crate::lib::binary_index::{read, split_path, write, MAGIC};
+    use crate::lib::file_entry::FileEntry;
+    use crate::lib::files_index::IndexHeader;
+    use crate::lib::ErrorKind;
+
+    fn entry(path: &[u8], inode: u64) -> FileEntry {
+        FileEntry {
+            relative_path: PathBuf::from(OsStr::from_bytes(path)),
+            fast_hash: Some(u128::MAX - inode as u128),
+            stat_size: 1 << 40,
+            stat_modified: SystemTime::UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789),
+            stat_created: SystemTime::UNIX_EPOCH,
+            stat_inode: inode,
+            stat_device: 2049,
+            stat_changed: SystemTime::UNIX_EPOCH + Duration::from_secs(5),
+            keep_separate: false,
+            digest: None,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(split_path(b"a/b/c"), (&b"a/b/"[..], &b"c"[..]));
+        assert_eq!(split_path(b"/c"), (&b"/"[..], &b"c"[..]));
+        assert_eq!(split_path(b"c"), (&b""[..], &b"c"[..]));
+
+        let header = IndexHeader {
+            format: "hardlink-deduplicator-index".to_owned(),
+            version: 2,
+            tool_version: "0.1.0".to_owned(),
+            fast_hash: "murmur3-x64-128".to_owned(),
+            base_path: PathBuf::from("/data"),
+            created: SystemTime::UNIX_EPOCH + Duration::from_secs(7),
+        };
+        let mut entries = vec![
+            entry(b"photos/2020/a.jpg", 1),
+            entry(b"photos/2020/b.jpg", 2),
+            entry(b"photos/2021/caf\xe9.jpg", 3),
+            entry(b"top", 4),
+            entry(b"/snapshots/top", 5),
+        ];
+        entries[1].fast_hash = None;
+        entries[1].keep_separate = true;
+        entries[2].digest = Some("sha256:00".to_owned());
+
+        let mut buf = vec![];
+        write(&mut buf, &header, &entries).unwrap();
+        assert!(buf.starts_with(MAGIC));
+        let (read_header, mut read_entries) = read(&mut &buf[MAGIC.len()..], &header.format).unwrap();
+        assert_eq!(read_header, header);
+        read_entries.sort();
+        entries.sort();
+        assert_eq!(read_entries, entries);
+
+        // every cut is noticed. A broken index is an error like a broken csv, not a file that
+        // doesn't match its hash.
+        for len in MAGIC.len()..buf.len() {
+            assert_eq!(read(&mut &buf[MAGIC.len()..len], &header.format).unwrap_err().kind(), ErrorKind::Other);
+        }
+        buf.push(0);
+        assert!(read(&mut &buf[MAGIC.len()..], &header.format).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use super::file_entry::FileEntry;
+use super::files_index::{FilesIndex, LoadedIndex};
+use super::fs::AbstractFs;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Problem {
+    // the same path appears in more than one row
+    DuplicatePath,
+    // stat failed: the file is gone, or we aren't allowed to look at it
+    Unreadable(String),
+    // size, timestamps or inode don't match the disk any more
+    DisagreesWithDisk,
+    // paths that share an inode have different hashes
+    InconsistentHash,
+    // another file on the same device has the same size, so this one should have been hashed
+    MissingHash,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Finding {
+    pub row: usize,
+    pub relative_path: PathBuf,
+    pub problem: Problem,
+}
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub rows: usize,
+    pub findings: Vec<Finding>,
+    // problems with the secondary maps of the index as loaded, see FilesIndex::sanity_check()
+    pub index_violations: Vec<String>,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty() && self.index_violations.is_empty()
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::DuplicatePath => write!(f, "path appears more than once in the index"),
+            Problem::Unreadable(e) => write!(f, "cannot stat file: {}", e),
+            Problem::DisagreesWithDisk => write!(f, "index row does not match the file on disk"),
+            Problem::InconsistentHash => write!(f, "hard links of this file have different hashes"),
+            Problem::MissingHash => write!(f, "file size is not unique but no hash is stored"),
+        }
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "checked {} index rows", self.rows)?;
+        for finding in &self.findings {
+            writeln!(f, "row {}: {:?}: {}", finding.row + 1, finding.relative_path, finding.problem)?;
+        }
+        for violation in &self.index_violations {
+            writeln!(f, "index: {}", violation)?;
+        }
+        if self.is_clean() {
+            writeln!(f, "no problems found")?;
+        } else {
+            writeln!(f, "{} problems found", self.findings.len() + self.index_violations.len())?;
+        }
+        Ok(())
+    }
+}
+
+pub fn check_entries<Fs: AbstractFs, P: AsRef<Path>>(fs: &Fs, base_path: P, entries: &[FileEntry]) -> CheckReport {
+    let mut findings = vec![];
+    let mut finding = |row: usize, problem: Problem| findings.push(Finding {
+        row,
+        relative_path: entries[row].relative_path.clone(),
+        problem,
+    });
+
+    let mut seen_paths = HashSet::new();
+    let mut by_inode: HashMap<(u64, u64), HashSet<Option<u128>>> = HashMap::new();
+    // files on different devices can't be linked, so they don't need to be told apart
+    let mut by_size: HashMap<(u64, u64), usize> = HashMap::new();
+    for (row, entry) in entries.iter().enumerate() {
+        if !seen_paths.insert(&entry.relative_path) {
+            finding(row, Problem::DuplicatePath);
+            continue;
+        }
+        by_inode.entry(entry.file_id()).or_default().insert(entry.fast_hash);
+        *by_size.entry((entry.stat_device, entry.stat_size)).or_default() += 1;
+        match entry.agrees_with_disk(fs, &base_path) {
+            Ok(true) => (),
+            Ok(false) => finding(row, Problem::DisagreesWithDisk),
+            Err(e) => finding(row, Problem::Unreadable(e.to_string())),
+        }
+    }
+
+    let mut seen_paths = HashSet::new();
+    for (row, entry) in entries.iter().enumerate() {
+        if !seen_paths.insert(&entry.relative_path) {
+            continue;
+        }
+        if by_inode[&entry.file_id()].len() > 1 {
+            finding(row, Problem::InconsistentHash);
+        } else if entry.fast_hash.is_none() && by_size[&(entry.stat_device, entry.stat_size)] > 1 {
+            finding(row, Problem::MissingHash);
+        }
+    }
+
+    // the maps get rebuilt from the rows on every load, so only the first copy of a path is used
+    let mut seen_paths = HashSet::new();
+    let unique_entries = entries.iter()
+        .filter(|e| seen_paths.insert(&e.relative_path))
+        .cloned()
+        .collect();
+    let index = FilesIndex::from_checked_entries(&base_path, unique_entries);
+
+    findings.sort_by_key(|f| f.row);
+    CheckReport {
+        rows: entries.len(),
+        findings,
+        index_violations: index.sanity_check(),
+    }
+}
+
+// drops every row with a problem; files that still exist are then treated as new on the next run,
+// so they get rehashed and deduplicated again. The index stays in the format it was in.
+pub fn repair<P: AsRef<Path>>(base_path: P, loaded: &LoadedIndex, report: &CheckReport) -> FilesIndex {
+    let bad_rows: HashSet<usize> = report.findings.iter()
+        .map(|f| f.row)
+        .collect();
+    let good_entries = loaded.entries.iter()
+        .enumerate()
+        .filter(|(row, _)| !bad_rows.contains(row))
+        .map(|(_, e)| e.clone())
+        .collect();
+    FilesIndex::from_loaded(base_path, LoadedIndex {
+        format: loaded.format,
+        header: loaded.header.clone(),
+        entries: good_entries,
+        fallback: loaded.fallback.clone(),
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    use crate::lib::check::{check_entries, repair, Problem};
+    use crate::lib::files_index::{FilesIndex, IndexFormat, LoadedIndex};
+    use crate::lib::fs::{AbstractFs, TestFs};
+
+    #[test]
+    fn test_clean_index() {
+        let mut test_fs = TestFs::default();
+        test_fs.set_cwd("/somefolder/");
+        let entries = vec![
+            test_fs.new_file_entry("/somefolder/test1", "asdf"),
+            test_fs.new_file_entry("/somefolder/test2", "qwerty"),
+        ];
+
+        let report = check_entries(&test_fs, "/somefolder/", &entries);
+        assert!(report.is_clean(), "{}", report);
+        assert_eq!(report.rows, 2);
+    }
+
+    #[test]
+    fn test_check_and_repair() {
+        let mut test_fs = TestFs::default();
+        test_fs.set_cwd("/somefolder/");
+        let mut hashed = test_fs.new_file_entry("/somefolder/test1", "asdf");
+        hashed.fast_hash = Some(3);
+        let mut missing_hash = test_fs.new_file_entry("/somefolder/test4", "1234");
+        missing_hash.fast_hash = None;
+        let mut inconsistent1 = test_fs.new_file_entry("/somefolder/test5", "12345");
+        inconsistent1.fast_hash = Some(1);
+        let mut inconsistent2 = inconsistent1.clone();
+        inconsistent2.relative_path = "test6".into();
+        inconsistent2.fast_hash = Some(2);
+        let entries = vec![
+            hashed.clone(),
+            hashed,
+            test_fs.new_file_entry("/somefolder/test2", "qwerty"),
+            test_fs.new_file_entry("/somefolder/test3", "gone!!!"),
+            missing_hash,
+            inconsistent1,
+            inconsistent2,
+        ];
+        test_fs.remove_file("/somefolder/test3").unwrap();
+        // test2 gets rewritten after it was indexed
+        test_fs.add_text_file("/somefolder/test2", "qwertyuiop");
+
+        let report = check_entries(&test_fs, "/somefolder/", &entries);
+        let problems: Vec<_> = report.findings.iter()
+            .map(|f| (f.row, f.problem.clone()))
+            .filter(|(_, p)| !matches!(p, Problem::Unreadable(_)))
+            .collect();
+        assert_eq!(problems, vec![
+            (1, Problem::DuplicatePath),
+            (2, Problem::DisagreesWithDisk),
+            (4, Problem::MissingHash),
+            (5, Problem::InconsistentHash),
+            (6, Problem::InconsistentHash),
+        ]);
+        assert!(report.findings.iter().any(|f| f.row == 3 && f.problem != Problem::DuplicatePath));
+        assert!(!report.index_violations.is_empty());
+
+        let loaded = LoadedIndex { format: IndexFormat::Csv, header: None, entries, fallback: None };
+        let index = repair("/somefolder/", &loaded, &report);
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+        assert!(index.get_by_relative_path(&Path::new("test1")).is_some());
+        assert!(index.get_by_relative_path(&Path::new("test2")).is_none());
+        assert!(index.get_by_relative_path(&Path::new("test3")).is_none());
+    }
+
+    #[test]
+    fn test_repair_keeps_format() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+        let entries = vec![
+            test_fs.new_file_entry("/somefolder/test1", "asdf"),
+            test_fs.new_file_entry("/somefolder/test2", "qwerty"),
+        ];
+        let created = SystemTime::UNIX_EPOCH + Duration::from_secs(5);
+        let mut index = FilesIndex::from_checked_entries(base_path, entries);
+        index.format = IndexFormat::Binary;
+        index.created = created;
+        index.save(&mut test_fs).unwrap();
+        test_fs.remove_file("/somefolder/test2").unwrap();
+
+        let loaded = FilesIndex::load(&test_fs, base_path).unwrap();
+        let report = check_entries(&test_fs, base_path, &loaded.entries);
+        let mut index = repair(base_path, &loaded, &report);
+        index.save(&mut test_fs).unwrap();
+        let loaded = FilesIndex::load(&test_fs, base_path).unwrap();
+        assert_eq!(loaded.format, IndexFormat::Binary);
+        assert_eq!(loaded.header.unwrap().created, created);
+        assert_eq!(loaded.entries.len(), 1);
+    }
+}
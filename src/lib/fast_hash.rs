@@ -1,13 +1,19 @@
 extern crate fasthash;
 
+use std::fmt;
 use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 use fasthash::HasherExt;
 use fasthash::murmur3;
 use fasthash::StreamHasher;
 
+use blake2::Blake2b;
+use sha2::{Digest, Sha256};
+
 use super::fs::AbstractFs;
-use super::Result;
+use super::{Result, ResultExt};
 
 pub fn hash_file<Fs: AbstractFs>(fs: &Fs, path: &Path) -> Result<u128> {
     let mut file = fs.open(path)?;
@@ -22,10 +28,128 @@ pub fn hash_to_hex_str(hash: u128) -> String {
     format!("{:032X}", hash)
 }
 
+// cryptographic hashes, for checksum manifests that other tools can read. Deduplication always uses
+// the murmur3 hash above.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HashAlgorithm {
+    Sha256,
+    // BLAKE2b-512, the default of b2sum
+    Blake2b,
+}
+
+impl HashAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake2b => "blake2b",
+        }
+    }
+
+    // manifests don't say which algorithm made them, but the digests have different lengths
+    pub fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            64 => Some(HashAlgorithm::Sha256),
+            128 => Some(HashAlgorithm::Blake2b),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake2b" | "b2" => Ok(HashAlgorithm::Blake2b),
+            _ => Err(format!("unknown hash algorithm {:?}, expected sha256 or blake2b", s)),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// lowercase hex, like sha256sum and b2sum print it
+pub fn digest_file<Fs: AbstractFs>(fs: &Fs, path: &Path, algorithm: HashAlgorithm) -> Result<String> {
+    let mut file = fs.open(path)?;
+    let digest = match algorithm {
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut file, &mut hasher).context("read", path)?;
+            hasher.finalize().to_vec()
+        }
+        HashAlgorithm::Blake2b => {
+            let mut hasher = Blake2b::new();
+            std::io::copy(&mut file, &mut hasher).context("read", path)?;
+            hasher.finalize().to_vec()
+        }
+    };
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// how a digest is stored in the index: "sha256:<hex>"
+pub fn format_digest(algorithm: HashAlgorithm, hex: &str) -> String {
+    format!("{}:{}", algorithm, hex)
+}
+
+pub fn parse_digest(digest: &str) -> Option<(HashAlgorithm, &str)> {
+    let mut parts = digest.splitn(2, ':');
+    let algorithm = parts.next()?.parse().ok()?;
+    Some((algorithm, parts.next()?))
+}
+
+// the hash of a file can also be kept in an xattr on the file itself, so it survives copies with
+// `rsync -X` and moves to other hosts that the index doesn't follow
+pub const HASH_XATTR_NAME: &str = "user.hardlink_deduplicator.hash";
+const HASH_XATTR_ALGORITHM: &str = "murmur3";
+
+// "murmur3 <hash> <size> <mtime secs>.<mtime nanos>", the size and mtime being what the file had
+// when it was hashed
+fn format_hash_xattr(hash: u128, size: u64, modified: SystemTime) -> String {
+    let modified = modified.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    format!("{} {} {} {}.{:09}", HASH_XATTR_ALGORITHM, hash_to_hex_str(hash), size,
+            modified.as_secs(), modified.subsec_nanos())
+}
+
+fn parse_hash_xattr(value: &str) -> Option<(u128, u64, SystemTime)> {
+    let mut fields = value.split(' ');
+    if fields.next()? != HASH_XATTR_ALGORITHM {
+        return None;
+    }
+    let hash = u128::from_str_radix(fields.next()?, 16).ok()?;
+    let size = fields.next()?.parse().ok()?;
+    let mut modified = fields.next()?.split('.');
+    let secs = modified.next()?.parse().ok()?;
+    let nanos = modified.next()?.parse().ok()?;
+    if fields.next().is_some() || modified.next().is_some() {
+        return None;
+    }
+    Some((hash, size, SystemTime::UNIX_EPOCH + Duration::new(secs, nanos)))
+}
+
+// the cached hash, if there is one and the file still has the size and mtime it was computed at
+pub fn read_cached_hash<Fs: AbstractFs>(fs: &Fs, path: &Path, size: u64, modified: SystemTime) -> Option<u128> {
+    let value = fs.get_xattr(path, HASH_XATTR_NAME).ok()??;
+    match parse_hash_xattr(std::str::from_utf8(&value).ok()?)? {
+        (hash, cached_size, cached_modified) if (cached_size, cached_modified) == (size, modified) => Some(hash),
+        _ => None,
+    }
+}
+
+pub fn write_cached_hash<Fs: AbstractFs>(fs: &mut Fs, path: &Path, hash: u128, size: u64, modified: SystemTime) -> Result<()> {
+    fs.set_xattr(path, HASH_XATTR_NAME, format_hash_xattr(hash, size, modified).as_bytes())
+}
+
 
 #[cfg(test)]
 mod test {
-    use crate::lib::fast_hash::hash_to_hex_str;
+    use std::time::{Duration, SystemTime};
+
+    use crate::lib::fast_hash::{digest_file, hash_to_hex_str, parse_digest, parse_hash_xattr, read_cached_hash, write_cached_hash, HashAlgorithm};
     use crate::lib::fs::TestFs;
 
     use super::hash_file;
@@ -64,4 +188,38 @@ mod test {
 
         assert!(hash_file(&test_fs, Path::new("missingfile")).is_err());
     }
+
+    #[test]
+    fn test_cached_hash() {
+        let mut test_fs = TestFs::default();
+        test_fs.add_text_file("filepath", "test");
+        let path = Path::new("filepath");
+        let modified = SystemTime::UNIX_EPOCH + Duration::new(1600000000, 5);
+        assert_eq!(read_cached_hash(&test_fs, path, 4, modified), None);
+
+        write_cached_hash(&mut test_fs, path, 1234, 4, modified).unwrap();
+        assert_eq!(read_cached_hash(&test_fs, path, 4, modified), Some(1234));
+        // computed when the file looked different
+        assert_eq!(read_cached_hash(&test_fs, path, 5, modified), None);
+        assert_eq!(read_cached_hash(&test_fs, path, 4, SystemTime::UNIX_EPOCH), None);
+
+        assert_eq!(parse_hash_xattr("murmur3 4D2 4 1600000000.000000005"), Some((1234, 4, modified)));
+        assert_eq!(parse_hash_xattr("sha256 4D2 4 1600000000.000000005"), None);
+        assert_eq!(parse_hash_xattr("murmur3 4D2 4 1600000000"), None);
+    }
+
+    #[test]
+    fn test_digest_file() {
+        let mut test_fs = TestFs::default();
+        test_fs.add_text_file("filepath", "test");
+        let path = Path::new("filepath");
+        assert_eq!(digest_file(&test_fs, path, HashAlgorithm::Sha256).unwrap(),
+                   "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08");
+        assert_eq!(digest_file(&test_fs, path, HashAlgorithm::Blake2b).unwrap(),
+                   "a71079d42853dea26e453004338670a53814b78137ffbed07603a41d76a483aa\
+                    9bc33b582f77d30a65e6f29a896c0411f38312e1d66e0bf16386c86a89bea572");
+        assert_eq!(parse_digest("sha256:abcd"), Some((HashAlgorithm::Sha256, "abcd")));
+        assert_eq!(parse_digest("md5:abcd"), None);
+        assert_eq!(HashAlgorithm::from_hex_len(128), Some(HashAlgorithm::Blake2b));
+    }
 }
@@ -5,11 +5,15 @@ use std::time::SystemTime;
 use serde::Deserialize;
 use serde::Serialize;
 
+use super::fast_hash::{parse_digest, HashAlgorithm};
 use super::fs;
 use super::Result;
 
 #[derive(Deserialize, Serialize, Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub struct FileEntry {
+    // relative to the base path, except for files in reference folders, which are absolute. See
+    // is_reference()
+    #[serde(with = "super::path_serde")]
     pub relative_path: PathBuf,
     pub fast_hash: Option<u128>,
     pub stat_size: u64,
@@ -19,6 +23,24 @@ pub struct FileEntry {
     pub stat_created: SystemTime,
     // in the case of non-duplicate files with the same size and hash, the inode resolves the duplicates
     pub stat_inode: u64,
+    // device and ctime make up the rest of the identity of the file, see same_identity(). Indexes
+    // written before these existed don't have them, see missing_identity()
+    #[serde(default)]
+    pub stat_device: u64,
+    #[serde(with = "humantime_serde", default = "unix_epoch")]
+    pub stat_changed: SystemTime,
+    // set on paths that were split out of a hard linked group on purpose, so they never get
+    // linked to anything again
+    #[serde(default)]
+    pub keep_separate: bool,
+    // a cryptographic hash for checksum manifests, like "sha256:<hex>", see fast_hash::format_digest().
+    // Only computed when a manifest is exported, or taken from one that was imported.
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+fn unix_epoch() -> SystemTime {
+    SystemTime::UNIX_EPOCH
 }
 
 impl FileEntry {
@@ -29,20 +51,42 @@ impl FileEntry {
         // and safely unwrap the option later
         let _ = relative_path.parent().ok_or("error finding relative folder")?;
 
-        let (size, modified, accessed, created, inode) = fs.metadata(&absolute_path)?;
+        let metadata = fs.metadata(&absolute_path)?;
 
         Ok(Self {
             relative_path: relative_path.to_owned(),
             fast_hash: None,
-            stat_size: size,
-            stat_modified: modified,
-            stat_created: created,
-            stat_inode: inode,
+            stat_size: metadata.size,
+            stat_modified: metadata.modified,
+            stat_created: metadata.created,
+            stat_inode: metadata.inode,
+            stat_device: metadata.device,
+            stat_changed: metadata.changed,
+            keep_separate: false,
+            digest: None,
         })
     }
 
+    // a file in a reference folder outside the base path, see is_reference()
+    pub fn new_reference<F: fs::AbstractFs, P: AsRef<Path>>(fs: &F, path: P) -> Result<Self> {
+        let absolute_path: PathBuf = fs.canonicalize(path)?;
+        Self::new(fs, "/", &absolute_path).map(|entry| Self {
+            relative_path: absolute_path,
+            ..entry
+        })
+    }
+
+    // what the same path looks like on disk now
+    pub fn stat_again<F: fs::AbstractFs, P1: AsRef<Path>>(&self, fs: &F, base_path: P1) -> Result<Self> {
+        if self.is_reference() {
+            Self::new_reference(fs, &self.relative_path)
+        } else {
+            Self::new(fs, &base_path, self.absolute_path(&base_path))
+        }
+    }
+
     pub fn reload_from_disk<F: fs::AbstractFs, P1: AsRef<Path>>(&self, fs: &F, base_path: P1) -> Result<Self> {
-        let mut new_entry = FileEntry::new(fs, &base_path, self.absolute_path(&base_path))?;
+        let mut new_entry = self.stat_again(fs, &base_path)?;
         if self.eq_except_hash(&new_entry) {
             new_entry.fast_hash = self.fast_hash;
         }
@@ -50,10 +94,16 @@ impl FileEntry {
     }
 
     pub fn agrees_with_disk<F: fs::AbstractFs, P1: AsRef<Path>>(&self, fs: &F, base_path: P1) -> Result<bool> {
-        let new_entry = FileEntry::new(fs, &base_path, self.absolute_path(&base_path))?;
+        let new_entry = self.stat_again(fs, &base_path)?;
         Ok(self.eq_except_hash(&new_entry))
     }
 
+    // files in reference folders can be linked to, but they are never renamed, removed or
+    // replaced themselves
+    pub fn is_reference(&self) -> bool {
+        self.relative_path.is_absolute()
+    }
+
     pub fn absolute_path<P: AsRef<Path>>(&self, base_path: P) -> PathBuf {
         base_path.as_ref().join(self.relative_path.as_path())
     }
@@ -68,15 +118,52 @@ impl FileEntry {
             &self.stat_size,
             &self.stat_modified,
             &self.stat_created,
-            &self.stat_inode
+            &self.stat_inode,
+            &self.stat_device,
+            &self.stat_changed,
         ) == (
             &other.relative_path,
             &other.stat_size,
             &other.stat_modified,
             &other.stat_created,
-            &other.stat_inode
+            &other.stat_inode,
+            &other.stat_device,
+            &other.stat_changed,
         )
     }
+
+    // the hex digest, if we have one made with this algorithm
+    pub fn digest_for(&self, algorithm: HashAlgorithm) -> Option<&str> {
+        match parse_digest(self.digest.as_ref()?) {
+            Some((a, hex)) if a == algorithm => Some(hex),
+            _ => None,
+        }
+    }
+
+    // (device, inode) is what makes two paths the same file
+    pub fn file_id(&self) -> (u64, u64) {
+        (self.stat_device, self.stat_inode)
+    }
+
+    // an inode number can be reused by a new file as soon as the old one is deleted, so on its own
+    // it doesn't say that two paths have the same contents. The ctime changes whenever the inode
+    // does, so if that matches as well, it's the same file and it hasn't been touched in between.
+    pub fn same_identity(&self, other: &Self) -> bool {
+        (self.stat_device, self.stat_inode, self.stat_changed) == (other.stat_device, other.stat_inode, other.stat_changed)
+    }
+
+    pub fn missing_identity(&self) -> bool {
+        self.stat_device == 0 && self.stat_changed == SystemTime::UNIX_EPOCH
+    }
+
+    // a copy of this entry with the device and ctime taken from `other`
+    pub fn with_identity_of(&self, other: &Self) -> Self {
+        Self {
+            stat_device: other.stat_device,
+            stat_changed: other.stat_changed,
+            ..self.clone()
+        }
+    }
 }
 
 #[cfg(test)]
@@ -84,7 +171,7 @@ mod test {
     use std::path::Path;
 
     use crate::lib::file_entry::FileEntry;
-    use crate::lib::fs::TestFs;
+    use crate::lib::fs::{AbstractFs, TestFs};
 
     #[test]
     fn test_new_file_entry() {
@@ -110,4 +197,21 @@ mod test {
         assert_eq!(file_hash.relative_path, Path::new("subfolder/file"));
         assert_eq!(file_hash.relative_folder(), Path::new("subfolder/"));
     }
+
+    #[test]
+    fn test_identity() {
+        let mut test_fs = TestFs::default();
+        test_fs.set_cwd("/somefolder/");
+
+        let f1 = test_fs.new_file_entry("/somefolder/file", "test");
+        assert!(!f1.missing_identity());
+        assert!(f1.same_identity(&f1.reload_from_disk(&test_fs, "/somefolder/").unwrap()));
+
+        // delete the file and put something else in its inode
+        test_fs.remove_file("/somefolder/file").unwrap();
+        test_fs.add_file_with_inode("/somefolder/other", b"asdf", f1.stat_inode);
+        let f2 = FileEntry::new(&test_fs, "/somefolder/", "/somefolder/other").unwrap();
+        assert_eq!(f1.file_id(), f2.file_id());
+        assert!(!f1.same_identity(&f2));
+    }
 }
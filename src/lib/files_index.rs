@@ -2,16 +2,52 @@ use std::collections::hash_map::RandomState;
 use std::collections::{HashMap, HashSet};
 use std::option::Option;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use std::io::BufReader;
 use std::io::BufRead;
-
+use std::io::Read;
+use std::str::FromStr;
+use std::fmt;
+
+use super::audit::{Change, Reconciliations, StaleEntries};
+use super::binary_index;
+#[cfg(feature = "sqlite")]
+use super::sqlite_index;
 use super::file_entry::FileEntry;
-use crate::lib::fs::AbstractFs;
-use crate::lib::Result;
+use crate::lib::fs::{AbstractFs, Metadata};
+use crate::lib::{Error, ErrorKind, Result, ResultExt};
+use serde::{Deserialize, Serialize};
 use std::hash::{Hash, Hasher};
 use fasthash::{murmur3, HasherExt};
-use crate::lib::fast_hash::hash_file;
-
+use crate::lib::fast_hash::{hash_file, read_cached_hash, write_cached_hash};
+use crate::lib::lock::IndexLock;
+use crate::lib::reconcile::{MetadataChange, Policy};
+
+pub const INDEX_FILE_NAME: &str = ".index_file.csv";
+pub const PREVIOUS_INDEX_FILE_NAME: &str = ".index_file.csv.prev";
+const TEMP_INDEX_FILE_NAME: &str = ".index_file.csv.tmp";
+pub const LOCK_FILE_NAME: &str = ".index_file.lock";
+pub const VERIFY_CURSOR_FILE_NAME: &str = ".index_file.verify_cursor";
+// used instead of INDEX_FILE_NAME by an index in IndexFormat::Sqlite
+pub const SQLITE_INDEX_FILE_NAME: &str = ".index_file.sqlite";
+const SQLITE_JOURNAL_FILE_NAME: &str = ".index_file.sqlite-journal";
+
+// version 1 is a bare csv of FileEntry rows. Since version 2 the rows come after an IndexHeader.
+pub const INDEX_FORMAT_VERSION: u32 = 2;
+const INDEX_FORMAT_NAME: &str = "hardlink-deduplicator-index";
+// the hash in FileEntry::fast_hash
+const FAST_HASH_NAME: &str = "murmur3-x64-128";
+
+// files that belong to the index itself, which we never want to deduplicate
+pub fn is_index_file<P: AsRef<Path>>(path: P) -> bool {
+    match path.as_ref().file_name().and_then(|name| name.to_str()) {
+        Some(name) => [
+            INDEX_FILE_NAME, PREVIOUS_INDEX_FILE_NAME, TEMP_INDEX_FILE_NAME, LOCK_FILE_NAME, VERIFY_CURSOR_FILE_NAME,
+            SQLITE_INDEX_FILE_NAME, SQLITE_JOURNAL_FILE_NAME,
+        ].contains(&name),
+        None => false,
+    }
+}
 
 fn group_by_with_value_func<C, KF, VF, K, V>(entries: C, key_func: KF, value_func: VF) -> HashMap<K, HashSet<V>>
     where C: IntoIterator, KF: Fn(&C::Item) -> Option<K>, VF: Fn(usize, &C::Item) -> V, K: Hash + Eq, V: Hash + Eq
@@ -33,18 +69,218 @@ fn group_by<C: IntoIterator, KF, K: Hash + Eq>(entries: C, f: KF) -> HashMap<K,
 }
 
 
-// invariant: all files in the files index are already deduplicated: they are either unique or they
-// have the same inode
+// options that change how files get added, set by the caller after loading the index
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    // also keep each hash in an xattr on the file, and use it instead of reading the file when the
+    // size and mtime still match. See fast_hash::HASH_XATTR_NAME.
+    pub xattr_cache: bool,
+    // files modified or changed after this may still be being written to, so they are left out
+    // until a later run
+    pub settle_cutoff: Option<SystemTime>,
+    // put the mtime of a directory back after one of its files was replaced by a link, so backup
+    // tools don't see every directory with a duplicate in it as changed
+    pub preserve_dir_times: bool,
+    // what a file's mtime and mode become when another path is linked to it
+    pub reconcile: Policy,
+}
+
+// both are read by load(), whichever one the index file is in, and save() writes the index back
+// the way it was. A new index is a csv.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IndexFormat {
+    Csv,
+    // see binary_index
+    Binary,
+    // see sqlite_index
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+impl Default for IndexFormat {
+    fn default() -> Self {
+        IndexFormat::Csv
+    }
+}
+
+impl FromStr for IndexFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(IndexFormat::Csv),
+            "binary" => Ok(IndexFormat::Binary),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(IndexFormat::Sqlite),
+            #[cfg(not(feature = "sqlite"))]
+            "sqlite" => Err("this build doesn't have the sqlite feature".to_owned()),
+            _ => Err(format!("unknown index format {:?}, expected csv or binary", s)),
+        }
+    }
+}
+
+impl fmt::Display for IndexFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IndexFormat::Csv => "csv",
+            IndexFormat::Binary => "binary",
+            #[cfg(feature = "sqlite")]
+            IndexFormat::Sqlite => "sqlite",
+        })
+    }
+}
+
+// what load() found in the index file
 #[derive(Debug, Clone)]
+pub struct LoadedIndex {
+    pub format: IndexFormat,
+    // None for an index from before there were headers, or if there is no index yet
+    pub header: Option<IndexHeader>,
+    pub entries: Vec<FileEntry>,
+    // why the index file couldn't be read, if the previous index was read instead
+    pub fallback: Option<String>,
+}
+
+// the first two lines of the index file, a csv header and a record of its own, followed by the
+// csv of the rows
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct IndexHeader {
+    pub format: String,
+    pub version: u32,
+    // the version of the tool that wrote it
+    pub tool_version: String,
+    pub fast_hash: String,
+    // where the index was when it was written. The rows are relative to it, so the folder can be
+    // moved.
+    #[serde(with = "super::path_serde")]
+    pub base_path: PathBuf,
+    // when the index was first written, it is kept from then on
+    #[serde(with = "humantime_serde")]
+    pub created: SystemTime,
+}
+
+// invariant: all files in the files index are already deduplicated: they are either unique or they
+// have the same inode. The exceptions are pinned files (reference folders and protected paths),
+// which never get linked to each other, see is_pinned(), and files on different devices.
+#[derive(Debug)]
 pub struct FilesIndex {
     pub base_path: PathBuf,
     entries: Vec<FileEntry>,
     by_relative_path: HashMap<PathBuf, usize>,
     by_size: HashMap<u64, HashSet<usize>>,
-    by_inode: HashMap<u64, HashSet<usize>>,
+    // keyed by (device, inode), see FileEntry::file_id()
+    by_inode: HashMap<(u64, u64), HashSet<usize>>,
     by_hash: HashMap<u128, HashSet<usize>>,
-    inode_by_size: HashMap<u64, HashSet<u64>>,
-    inode_by_hash: HashMap<u128, HashSet<u64>>,
+    inode_by_size: HashMap<u64, HashSet<(u64, u64)>>,
+    inode_by_hash: HashMap<u128, HashSet<(u64, u64)>>,
+    // held from for_base_path() until save()
+    lock: Option<IndexLock>,
+    // hard linked groups that were modified in place since the last run, found when loading
+    pub edited_groups: Vec<EditedGroup>,
+    // the old rows of edited groups that haven't been split yet. They are saved as they are, so
+    // the next run reports the group again, and their paths are left alone until then.
+    held_back: Vec<FileEntry>,
+    // rows that were dropped when loading, and why
+    pub stale: StaleEntries,
+    // files that changed while they were being hashed. They are left out of the index, so the
+    // next run tries them again.
+    pub unstable: Vec<PathBuf>,
+    // files that changed between being compared and being linked, see retry_queued()
+    retry_queue: Vec<PathBuf>,
+    // what config.reconcile changed on linked files
+    pub reconciled: Reconciliations,
+    // see IndexHeader
+    pub created: SystemTime,
+    // what save() writes
+    pub format: IndexFormat,
+    pub config: Config,
+    // see LoadedIndex
+    pub fallback: Option<String>,
+}
+
+// only a path that isn't there is false, any other error is returned
+fn exists<Fs: AbstractFs>(fs: &Fs, path: &Path) -> Result<bool> {
+    match fs.metadata(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn check_header(header: &IndexHeader) -> Result<()> {
+    if header.format != INDEX_FORMAT_NAME {
+        return Err(format!("not an index file, the format is {:?}", header.format).into());
+    }
+    if header.version < 1 {
+        return Err(Error::unsupported_index(format!("there is no index format version {}", header.version)));
+    }
+    if header.version > INDEX_FORMAT_VERSION {
+        return Err(Error::unsupported_index(format!(
+            "the index was written by version {} of this tool in format version {}, but this is version {}, which only reads up to format version {}",
+            header.tool_version, header.version, env!("CARGO_PKG_VERSION"), INDEX_FORMAT_VERSION)));
+    }
+    if header.fast_hash != FAST_HASH_NAME {
+        return Err(Error::unsupported_index(format!("the index uses {} hashes, not {}", header.fast_hash, FAST_HASH_NAME)));
+    }
+    Ok(())
+}
+
+// brings rows from an older format version up to the current one. Each version only has to
+// handle the step from the one before it.
+fn migrate(version: u32, entries: Vec<FileEntry>) -> Result<Vec<FileEntry>> {
+    let mut entries = entries;
+    for from in version..INDEX_FORMAT_VERSION {
+        entries = match from {
+            // only the header is new. Rows that are older still, from before the device, ctime,
+            // keep_separate and digest columns, get defaults for those when they are read, and
+            // from_entries() takes the device and ctime from the disk.
+            1 => entries,
+            _ => return Err(Error::unsupported_index(format!("no migration from index format version {}", from))),
+        };
+    }
+    Ok(entries)
+}
+
+// several paths that share an inode, and the inode was written to since it was indexed. Writing to
+// any one of the paths changes all of them, which people don't always expect.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EditedGroup {
+    pub relative_paths: Vec<PathBuf>,
+    pub old_size: u64,
+    pub new_size: u64,
+    pub old_modified: SystemTime,
+    pub new_modified: SystemTime,
+}
+
+fn find_edited_groups(entries: &[FileEntry], on_disk: &[Result<FileEntry>]) -> Vec<EditedGroup> {
+    let groups = group_by(entries, |e| Some(e.file_id()));
+    let mut edited_groups: Vec<EditedGroup> = groups.values()
+        .filter(|rows| rows.len() > 1)
+        .filter_map(|rows| {
+            let mut rows: Vec<usize> = rows.iter().cloned().collect();
+            rows.sort_by_key(|&row| &entries[row].relative_path);
+            // the paths that still point at the same inode, but with different contents
+            let edited: Vec<(&FileEntry, &FileEntry)> = rows.iter()
+                .filter_map(|&row| Some((&entries[row], on_disk[row].as_ref().ok()?)))
+                .filter(|(e, disk)| e.file_id() == disk.file_id())
+                .filter(|(e, disk)| (e.stat_size, e.stat_modified) != (disk.stat_size, disk.stat_modified))
+                .collect();
+            let (old, new) = edited.first()?;
+            if edited.len() < 2 {
+                // only one path is left, so nobody got surprised
+                return None;
+            }
+            Some(EditedGroup {
+                relative_paths: edited.iter().map(|(e, _)| e.relative_path.clone()).collect(),
+                old_size: old.stat_size,
+                new_size: new.stat_size,
+                old_modified: old.stat_modified,
+                new_modified: new.stat_modified,
+            })
+        })
+        .collect();
+    edited_groups.sort_by(|a, b| a.relative_paths.cmp(&b.relative_paths));
+    edited_groups
 }
 
 impl FilesIndex {
@@ -58,14 +294,69 @@ impl FilesIndex {
             by_hash: Default::default(),
             inode_by_size: Default::default(),
             inode_by_hash: Default::default(),
+            lock: None,
+            edited_groups: vec![],
+            held_back: vec![],
+            stale: Default::default(),
+            unstable: vec![],
+            retry_queue: vec![],
+            reconciled: Default::default(),
+            created: SystemTime::now(),
+            format: Default::default(),
+            config: Default::default(),
+            fallback: None,
         }
     }
 
     fn from_entries<Fs: AbstractFs, P: AsRef<Path>>(fs: &Fs, base_path: P, entries: &[FileEntry]) -> Self {
-        let entries: Vec<FileEntry> = entries.iter()
-            .filter(|e| e.agrees_with_disk(fs, &base_path).ok() == Some(true))
+        let on_disk: Vec<Result<FileEntry>> = entries.iter()
+            .map(|e| e.stat_again(fs, &base_path))
+            .collect();
+        let edited_groups = find_edited_groups(entries, &on_disk);
+        let edited_paths: HashSet<&PathBuf> = edited_groups.iter()
+            .flat_map(|g| g.relative_paths.iter())
+            .collect();
+        let held_back = entries.iter()
+            .filter(|e| edited_paths.contains(&e.relative_path))
             .cloned()
             .collect();
+
+        let mut stale = StaleEntries::default();
+        let entries: Vec<FileEntry> = entries.iter()
+            .zip(on_disk)
+            .filter_map(|(e, on_disk)| {
+                // held back and reported as an edited group instead, every run until it is split
+                if edited_paths.contains(&e.relative_path) {
+                    return None;
+                }
+                let change = match Change::classify(e, &on_disk) {
+                    None => return Some(e.clone()),
+                    Some(change) => change,
+                };
+                // rows from before we recorded the device and ctime: if everything else still
+                // matches, trust them this once instead of rehashing the whole tree. Any other row
+                // with a different device or ctime is a different or changed file.
+                if let (true, Ok(on_disk)) = (e.missing_identity(), &on_disk) {
+                    let migrated = e.with_identity_of(on_disk);
+                    if !migrated.missing_identity() && migrated.eq_except_hash(on_disk) {
+                        return Some(migrated);
+                    }
+                }
+                stale.push(e.clone(), change);
+                None
+            })
+            .collect();
+
+        let mut index = Self::from_checked_entries(base_path, entries);
+        index.held_back = held_back;
+        index.edited_groups = edited_groups;
+        index.stale = stale;
+        index
+    }
+
+    // builds the secondary maps without looking at the disk; the caller is responsible for the
+    // entries being up to date and having unique paths
+    pub fn from_checked_entries<P: AsRef<Path>>(base_path: P, entries: Vec<FileEntry>) -> Self {
         let by_path = entries.iter()
             .enumerate()
             .map(|(i, e)| (e.relative_path.to_owned(), i))
@@ -75,128 +366,381 @@ impl FilesIndex {
             base_path: base_path.as_ref().to_path_buf(),
             by_relative_path: by_path,
             by_size: group_by(&entries, |e| Some(e.stat_size)),
-            by_inode: group_by(&entries, |e| Some(e.stat_inode)),
+            by_inode: group_by(&entries, |e| Some(e.file_id())),
             by_hash: group_by(&entries, |e| e.fast_hash),
             inode_by_size: group_by_with_value_func(
                 &entries,
                 |e| Some(e.stat_size),
-                |_, e| e.stat_inode,
+                |_, e| e.file_id(),
             ),
             inode_by_hash: group_by_with_value_func(
                 &entries,
                 |e| e.fast_hash,
-                |_, e| e.stat_inode,
+                |_, e| e.file_id(),
             ),
             entries,
+            lock: None,
+            edited_groups: vec![],
+            held_back: vec![],
+            stale: Default::default(),
+            unstable: vec![],
+            retry_queue: vec![],
+            reconciled: Default::default(),
+            created: SystemTime::now(),
+            format: Default::default(),
+            config: Default::default(),
+            fallback: None,
         }
     }
 
-    pub fn for_base_path<Fs: AbstractFs, P: AsRef<Path>>(fs: &Fs, base_path: P) -> Result<Self> {
-        let mut index_path = base_path.as_ref().to_owned();
-        index_path.push(".index_file.csv");
+    // locks the base path against other runs until the index is saved
+    pub fn for_base_path<Fs: AbstractFs, P: AsRef<Path>>(fs: &mut Fs, base_path: P, wait_for_lock: bool) -> Result<Self> {
+        let lock = IndexLock::acquire(fs, &base_path, wait_for_lock)?;
+        let loaded = match Self::load(fs, &base_path) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                if let Some(lock) = lock {
+                    lock.release(fs)?;
+                }
+                return Err(e);
+            }
+        };
+        let mut index = if loaded.entries.is_empty() {
+            Self::new(base_path)
+        } else {
+            Self::from_entries(fs, base_path, &loaded.entries)
+        };
+        if let Some(header) = loaded.header {
+            index.created = header.created;
+        }
+        index.format = loaded.format;
+        index.fallback = loaded.fallback;
+        index.lock = lock;
+        Ok(index)
+    }
+
+    // rewrites the index in the other format. The rows are kept exactly as they are, without
+    // checking them against the disk. Returns how many there are, and the fallback, see
+    // LoadedIndex.
+    pub fn convert<Fs: AbstractFs, P: AsRef<Path>>(fs: &mut Fs, base_path: P, format: IndexFormat, wait_for_lock: bool) -> Result<(usize, Option<String>)> {
+        let lock = IndexLock::acquire(fs, &base_path, wait_for_lock)?;
+        let loaded = match Self::load(fs, &base_path) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                if let Some(lock) = lock {
+                    lock.release(fs)?;
+                }
+                return Err(e);
+            }
+        };
+        let rows = loaded.entries.len();
+        let mut index = Self::from_loaded(base_path, loaded);
+        index.format = format;
+        index.lock = lock;
+        index.save(fs)?;
+        Ok((rows, index.fallback.take()))
+    }
 
-        // if we can't read the index file, just make a new empty index
-        if fs.metadata(&index_path).is_err() {
-            return Ok(Self::new(base_path));
+    // like from_checked_entries(), but saves in the format the index was loaded from, and keeps
+    // its header
+    pub fn from_loaded<P: AsRef<Path>>(base_path: P, loaded: LoadedIndex) -> Self {
+        let mut index = Self::from_checked_entries(base_path, loaded.entries);
+        if let Some(header) = loaded.header {
+            index.created = header.created;
         }
+        index.format = loaded.format;
+        index.fallback = loaded.fallback;
+        index
+    }
 
-        let file = fs.open(&index_path)?;
-        let mut rdr = csv::Reader::from_reader(file);
+    // the index file exactly as it was saved, without checking the rows against the disk
+    pub fn load<Fs: AbstractFs, P: AsRef<Path>>(fs: &Fs, base_path: P) -> Result<LoadedIndex> {
+        let index_path = base_path.as_ref().join(INDEX_FILE_NAME);
+        let previous_index_path = base_path.as_ref().join(PREVIOUS_INDEX_FILE_NAME);
+        let sqlite_index_path = base_path.as_ref().join(SQLITE_INDEX_FILE_NAME);
 
-        let entries: Vec<FileEntry> = rdr.deserialize()
-            .collect::<std::result::Result<_, _>>()?;
+        if exists(fs, &sqlite_index_path)? {
+            return Self::read_sqlite(fs, base_path.as_ref());
+        }
+        // no index yet, so start a new empty one. An index we can't get at is an error though,
+        // saving over it would lose it.
+        if !exists(fs, &index_path)? {
+            return Ok(LoadedIndex { format: IndexFormat::Csv, header: None, entries: vec![], fallback: None });
+        }
+
+        match Self::read_entries(fs, &index_path) {
+            Ok(loaded) => Ok(loaded),
+            // the previous index is older still, and saving over a newer index would lose whatever
+            // the newer version keeps in it
+            Err(e) if e.kind() == ErrorKind::UnsupportedIndex => Err(e),
+            Err(e) if fs.metadata(&previous_index_path).is_ok() => {
+                let mut loaded = Self::read_entries(fs, &previous_index_path)?;
+                loaded.fallback = Some(format!("{}, using {:?} instead", e, previous_index_path));
+                Ok(loaded)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_entries<Fs: AbstractFs>(fs: &Fs, index_path: &Path) -> Result<LoadedIndex> {
+        let mut file = fs.open(index_path)?;
+        let mut magic = vec![];
+        (&mut file).take(binary_index::MAGIC.len() as u64).read_to_end(&mut magic).context("read index", index_path)?;
+        if magic == binary_index::MAGIC {
+            let (header, entries) = binary_index::read(&mut BufReader::new(file), INDEX_FORMAT_NAME)
+                .context("read index", index_path)?;
+            check_header(&header).context("read index", index_path)?;
+            let entries = migrate(header.version, entries).context("read index", index_path)?;
+            return Ok(LoadedIndex { format: IndexFormat::Binary, header: Some(header), entries, fallback: None });
+        }
+        let (header, entries) = Self::read_csv(std::io::Cursor::new(magic).chain(file), index_path)?;
+        Ok(LoadedIndex { format: IndexFormat::Csv, header, entries, fallback: None })
+    }
+
+    // sqlite reads the file itself, not through fs
+    #[cfg(feature = "sqlite")]
+    fn read_sqlite<Fs: AbstractFs>(fs: &Fs, base_path: &Path) -> Result<LoadedIndex> {
+        let index_path = &Self::check_sqlite_files(fs, base_path)?;
+        let (header, entries) = sqlite_index::load(index_path).context("read index", index_path)?;
+        check_header(&header).context("read index", index_path)?;
+        let entries = migrate(header.version, entries).context("read index", index_path)?;
+        Ok(LoadedIndex { format: IndexFormat::Sqlite, header: Some(header), entries, fallback: None })
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn read_sqlite<Fs: AbstractFs>(_fs: &Fs, base_path: &Path) -> Result<LoadedIndex> {
+        Err(Error::unsupported_index(format!(
+            "{:?} is a sqlite index, but this build doesn't have the sqlite feature", base_path.join(SQLITE_INDEX_FILE_NAME))))
+    }
+
+    // the database and its journal may not exist yet, but they must not be anything other than a
+    // plain file, like a symlink that fs wouldn't follow. Returns the database path.
+    #[cfg(feature = "sqlite")]
+    fn check_sqlite_files<Fs: AbstractFs>(fs: &Fs, base_path: &Path) -> Result<PathBuf> {
+        for name in [SQLITE_INDEX_FILE_NAME, SQLITE_JOURNAL_FILE_NAME].iter() {
+            match fs.metadata(base_path.join(name)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(base_path.join(SQLITE_INDEX_FILE_NAME))
+    }
 
-        Ok(Self::from_entries(fs, base_path, &entries))
+    fn read_csv<R: Read>(reader: R, index_path: &Path) -> Result<(Option<IndexHeader>, Vec<FileEntry>)> {
+        // the header and the rows have different columns
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(reader);
+        let mut records = rdr.records();
+
+        let mut columns = match records.next() {
+            Some(columns) => columns.context("read index", index_path)?,
+            None => return Ok((None, vec![])),
+        };
+        let mut header = None;
+        if columns.get(0) == Some("format") {
+            let values = records.next()
+                .ok_or_else(|| Error::from("the header is cut off"))
+                .and_then(|values| values.map_err(Error::from))
+                .context("read index", index_path)?;
+            let h: IndexHeader = values.deserialize(Some(&columns)).context("read index", index_path)?;
+            check_header(&h).context("read index", index_path)?;
+            header = Some(h);
+            columns = match records.next() {
+                Some(columns) => columns.context("read index", index_path)?,
+                None => return Ok((header, vec![])),
+            };
+        }
+        let entries: Vec<FileEntry> = records
+            .map(|record| record.and_then(|record| record.deserialize(Some(&columns))))
+            .collect::<std::result::Result<_, _>>()
+            .context("read index", index_path)?;
+        let version = header.as_ref().map_or(1, |h| h.version);
+        Ok((header, migrate(version, entries).context("read index", index_path)?))
     }
 
+    fn header(&self) -> IndexHeader {
+        IndexHeader {
+            format: INDEX_FORMAT_NAME.to_owned(),
+            version: INDEX_FORMAT_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+            fast_hash: FAST_HASH_NAME.to_owned(),
+            base_path: self.base_path.clone(),
+            created: self.created,
+        }
+    }
+
+    // a sqlite index can't be streamed, so it gets the csv
     pub fn save_to_writer<W: std::io::Write>(&self, writer: &mut W) -> Result<()> {
+        let header = self.header();
+        let entries = self.entries.iter().chain(&self.held_back);
+        if self.format == IndexFormat::Binary {
+            return binary_index::write(writer, &header, entries);
+        }
+        let mut wtr = csv::Writer::from_writer(&mut *writer);
+        wtr.serialize(header)?;
+        wtr.flush()?;
+        drop(wtr);
+
         let mut wtr = csv::Writer::from_writer(writer);
-        for entry in &self.entries {
+        for entry in entries {
             wtr.serialize(entry)?;
         }
         Ok(())
     }
 
-    pub fn save<Fs: AbstractFs>(&self, fs: &mut Fs) -> Result<()> {
-        let mut index_path = self.base_path.clone();
-        index_path.push(".index_file.csv");
+    // the new index is written next to the old one and renamed over it, so a crash part way
+    // through leaves either the old or the new index in place, never a truncated one
+    pub fn save<Fs: AbstractFs>(&mut self, fs: &mut Fs) -> Result<()> {
+        let index_path = self.base_path.join(INDEX_FILE_NAME);
+        let previous_index_path = self.base_path.join(PREVIOUS_INDEX_FILE_NAME);
+        let temp_index_path = self.base_path.join(TEMP_INDEX_FILE_NAME);
+        let sqlite_index_path = self.base_path.join(SQLITE_INDEX_FILE_NAME);
+
+        // sqlite updates its file in a transaction of its own, and only writes the rows that
+        // changed. It goes around fs, so fs is asked first whether it would allow the writes.
+        #[cfg(feature = "sqlite")]
+        if self.format == IndexFormat::Sqlite {
+            fs.check_writable(&sqlite_index_path)?;
+            fs.check_writable(self.base_path.join(SQLITE_JOURNAL_FILE_NAME))?;
+            Self::check_sqlite_files(fs, &self.base_path)?;
+            sqlite_index::save(&sqlite_index_path, &self.header(), self.entries.iter().chain(&self.held_back))
+                .context("write index", &sqlite_index_path)?;
+            // load() would never look at them again
+            for path in [&index_path, &previous_index_path].iter() {
+                if fs.metadata(path).is_ok() {
+                    fs.remove_file(path)?;
+                }
+            }
+            fs.sync_dir(&self.base_path)?;
+            return self.release_lock(fs);
+        }
 
         let mut buf = vec![];
         self.save_to_writer(&mut buf)?;
 
-        fs.write_to_file(index_path, &buf)?;
-        Ok(())
+        fs.write_to_file(&temp_index_path, &buf)?;
+        // keep the current index around as a fallback, in case the new one turns out unreadable
+        if fs.metadata(&index_path).is_ok() {
+            if fs.metadata(&previous_index_path).is_ok() {
+                fs.remove_file(&previous_index_path)?;
+            }
+            fs.hard_link(&index_path, &previous_index_path)?;
+        }
+        fs.rename(&temp_index_path, &index_path)?;
+        // load() looks for a sqlite index first, so it has to go once the index is converted back
+        if fs.metadata(&sqlite_index_path).is_ok() {
+            fs.remove_file(&sqlite_index_path)?;
+        }
+        fs.sync_dir(&self.base_path)?;
+        self.release_lock(fs)
+    }
+
+    // for runs that only read the index and never save it
+    pub fn release_lock<Fs: AbstractFs>(&mut self, fs: &mut Fs) -> Result<()> {
+        match self.lock.take() {
+            Some(lock) => lock.release(fs),
+            None => Ok(()),
+        }
     }
 
-    pub fn sanity_check(&self) {
+    // returns a description of every broken invariant, so an empty list means the index is healthy
+    pub fn sanity_check(&self) -> Vec<String> {
+        let mut violations = vec![];
+
         // check starting from the file entries
         for (i, entry) in self.entries.iter().enumerate() {
-            assert_eq!(self.by_relative_path.get(&entry.relative_path).unwrap(), &i);
-            assert!(self.by_size.get(&entry.stat_size).unwrap().contains(&i));
-            assert!(self.by_inode.get(&entry.stat_inode).unwrap().contains(&i));
-            assert!(self.inode_by_size.get(&entry.stat_size).unwrap().contains(&entry.stat_inode));
+            let path = entry.relative_path.to_string_lossy();
+            if self.by_relative_path.get(&entry.relative_path) != Some(&i) {
+                violations.push(format!("{:?} is missing from the path index", path));
+            }
+            if !self.by_size.get(&entry.stat_size).map_or(false, |idxs| idxs.contains(&i)) {
+                violations.push(format!("{:?} is missing from the size index", path));
+            }
+            if !self.by_inode.get(&entry.file_id()).map_or(false, |idxs| idxs.contains(&i)) {
+                violations.push(format!("{:?} is missing from the inode index", path));
+            }
+            if !self.inode_by_size.get(&entry.stat_size).map_or(false, |inodes| inodes.contains(&entry.file_id())) {
+                violations.push(format!("{:?} inode {} is missing from the size index", path, entry.stat_inode));
+            }
             if let Some(hash) = entry.fast_hash {
-                assert!(self.by_hash.contains_key(&hash), "{:?} missing completely from hash index", entry);
-                assert!(self.by_hash.get(&hash).unwrap().contains(&i), "{:?} missing from hash index", entry);
-                assert!(self.inode_by_hash.get(&hash).unwrap().contains(&entry.stat_inode));
+                if !self.by_hash.get(&hash).map_or(false, |idxs| idxs.contains(&i)) {
+                    violations.push(format!("{:?} is missing from the hash index", path));
+                }
+                if !self.inode_by_hash.get(&hash).map_or(false, |inodes| inodes.contains(&entry.file_id())) {
+                    violations.push(format!("{:?} inode {} is missing from the hash index", path, entry.stat_inode));
+                }
             }
         }
 
         // and now check starting from the indexes
-        self.by_size.iter()
-            .flat_map(|(key, idxs)|
-                idxs
-                    .iter()
-                    .map(move |&idx| (key, idx))
-            )
-            .map(|(key, idx)| (key, &self.entries[idx]))
-            .for_each(|(key, entry)| {
-                assert_eq!(key, &entry.stat_size);
-            });
-        self.by_inode.iter()
-            .flat_map(|(key, idxs)|
-                idxs
-                    .iter()
-                    .map(move |&idx| (key, idx))
-            )
-            .map(|(key, idx)| (key, &self.entries[idx]))
-            .for_each(|(key, entry)| {
-                assert_eq!(key, &entry.stat_inode);
-            });
-        self.by_hash.iter()
-            .flat_map(|(key, idxs)|
-                idxs
-                    .iter()
-                    .map(move |&idx| (key, idx))
-            )
-            .map(|(key, idx)| (key, &self.entries[idx]))
-            .for_each(|(key, entry)| {
-                assert_eq!(key, &entry.fast_hash.unwrap());
-            });
+        let mut check_index = |name: &str, idxs: &HashSet<usize>, matches: &dyn Fn(&FileEntry) -> bool| {
+            for &idx in idxs {
+                match self.entries.get(idx) {
+                    None => violations.push(format!("{} index points at missing entry {}", name, idx)),
+                    Some(entry) if !matches(entry) => violations.push(format!(
+                        "{} index has the wrong key for {:?}", name, entry.relative_path.to_string_lossy())),
+                    Some(_) => (),
+                }
+            }
+        };
+        for (key, idxs) in self.by_size.iter() {
+            check_index("size", idxs, &|e| &e.stat_size == key);
+        }
+        for (key, idxs) in self.by_inode.iter() {
+            check_index("inode", idxs, &|e| &e.file_id() == key);
+        }
+        for (key, idxs) in self.by_hash.iter() {
+            check_index("hash", idxs, &|e| e.fast_hash.as_ref() == Some(key));
+        }
 
         // and check that the hashes are consistent, just to make sure
-        for (_, idxs) in self.by_inode.iter() {
+        for ((_, inode), idxs) in self.by_inode.iter() {
             let hashes: HashSet<_> = idxs.iter()
-                .map(|&i| &self.entries[i].fast_hash)
+                .filter_map(|&i| self.entries.get(i))
+                .map(|e| &e.fast_hash)
                 .collect();
-            assert_eq!(hashes.len(), 1);
+            if hashes.len() > 1 {
+                violations.push(format!("paths with inode {} have different hashes", inode));
+            }
         }
 
-        // check that things are hashed when they should be
+        // check that things are hashed when they should be, files on other devices don't count
         self.by_size.iter()
             .filter(|(_, idxs)| idxs.len() > 1)
             .flat_map(|(_, idxs)|
                 idxs
                     .iter()
-                    .map(move |&idx| &self.entries[idx])
+                    .filter_map(move |&idx| self.entries.get(idx))
+                    .filter(move |entry| idxs.iter()
+                        .filter_map(|&idx| self.entries.get(idx))
+                        .filter(|other| other.stat_device == entry.stat_device)
+                        .count() > 1)
             )
+            .filter(|entry| entry.fast_hash.is_none())
             .for_each(|entry| {
-                assert!(&entry.fast_hash.is_some(),
-                        "file relative_path={:?} is missing hash (size {} is non-unique)",
-                        entry.relative_path.to_string_lossy(),
-                        entry.stat_size,
-                );
+                violations.push(format!(
+                    "file relative_path={:?} is missing hash (size {} is non-unique)",
+                    entry.relative_path.to_string_lossy(),
+                    entry.stat_size,
+                ));
             });
+
+        violations
+    }
+
+    // true for paths of an edited group that hasn't been split, which shouldn't be indexed again yet
+    pub fn is_held_back<P: AsRef<Path>>(&self, relative_path: P) -> bool {
+        self.held_back.iter().any(|e| e.relative_path == relative_path.as_ref())
+    }
+
+    pub fn entries(&self) -> &[FileEntry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
     }
 
     pub fn get_by_relative_path<P: AsRef<Path>>(&self, relative_path: &P) -> Option<&FileEntry> {
@@ -212,13 +756,7 @@ impl FilesIndex {
                 return &self.entries[idx];
             }
             // new replacement for existing index, remove existing stuff
-            self.by_size.get_mut(&existing_entry.stat_size).unwrap().remove(&idx);
-            self.by_inode.get_mut(&existing_entry.stat_inode).unwrap().remove(&idx);
-            self.inode_by_size.get_mut(&existing_entry.stat_size).unwrap().remove(&existing_entry.stat_inode);
-            if let Some(hash) = existing_entry.fast_hash {
-                self.by_hash.get_mut(&hash).unwrap().remove(&idx);
-                self.inode_by_hash.get_mut(&hash).unwrap().remove(&existing_entry.stat_inode);
-            }
+            self.unindex_entry(idx);
             // and re-insert, because the entry has probably changed
             self.entries[idx] = file_entry.clone();
             idx
@@ -227,56 +765,433 @@ impl FilesIndex {
             self.entries.push(file_entry.clone());
             self.entries.len() - 1
         };
-        // add to indexes
+        self.index_entry(idx);
+        &self.entries[idx]
+    }
+
+    // the other paths with the same contents, as far as the index knows: hard links of the file,
+    // and pinned or split files with the same size and hash
+    pub fn duplicates_of<P: AsRef<Path>>(&self, relative_path: &P) -> Vec<&FileEntry> {
+        let entry = match self.get_by_relative_path(relative_path) {
+            Some(entry) => entry,
+            None => return vec![],
+        };
+        let mut idxs: HashSet<usize> = self.by_inode.get(&entry.file_id()).cloned().unwrap_or_default();
+        if let Some(hash) = entry.fast_hash {
+            idxs.extend(self.by_hash.get(&hash).into_iter().flatten()
+                .filter(|&&i| self.entries[i].stat_size == entry.stat_size));
+        }
+        let mut duplicates: Vec<&FileEntry> = idxs.into_iter()
+            .map(|i| &self.entries[i])
+            .filter(|e| e.relative_path != entry.relative_path)
+            .collect();
+        duplicates.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        duplicates
+    }
+
+    // digests aren't part of any of the maps, so they can be set in place
+    pub fn set_digest<P: AsRef<Path>>(&mut self, relative_path: P, digest: String) -> bool {
+        match self.by_relative_path.get(relative_path.as_ref()) {
+            Some(&idx) => {
+                self.entries[idx].digest = Some(digest);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // drops the entries of reference folders that weren't given for this run, so nothing gets
+    // linked to them any more
+    pub fn retain_references<P: AsRef<Path>>(&mut self, reference_roots: &[P]) {
+        let dropped: Vec<PathBuf> = self.entries.iter()
+            .filter(|e| e.is_reference())
+            .filter(|e| !reference_roots.iter().any(|root| e.relative_path.starts_with(root)))
+            .map(|e| e.relative_path.clone())
+            .collect();
+        for relative_path in dropped {
+            self.remove_entry(relative_path);
+        }
+    }
+
+    pub fn remove_entry<P: AsRef<Path>>(&mut self, relative_path: P) -> Option<FileEntry> {
+        let idx = *self.by_relative_path.get(relative_path.as_ref())?;
+        let last_idx = self.entries.len() - 1;
+        self.unindex_entry(idx);
+        if idx != last_idx {
+            // the last entry is about to be moved into the hole, so it gets a new index
+            self.unindex_entry(last_idx);
+        }
+        let removed = self.entries.swap_remove(idx);
+        if idx != last_idx {
+            self.index_entry(idx);
+        }
+        Some(removed)
+    }
+
+    fn index_entry(&mut self, idx: usize) {
+        let file_entry = &self.entries[idx];
         self.by_relative_path.insert(file_entry.relative_path.to_owned(), idx);
         self.by_size.entry(file_entry.stat_size).or_default().insert(idx);
-        self.by_inode.entry(file_entry.stat_inode).or_default().insert(idx);
-        self.inode_by_size.entry(file_entry.stat_size).or_default().insert(file_entry.stat_inode);
+        self.by_inode.entry(file_entry.file_id()).or_default().insert(idx);
+        self.inode_by_size.entry(file_entry.stat_size).or_default().insert(file_entry.file_id());
         if let Some(hash) = file_entry.fast_hash {
             self.by_hash.entry(hash).or_default().insert(idx);
-            self.inode_by_hash.entry(hash).or_default().insert(file_entry.stat_inode);
+            self.inode_by_hash.entry(hash).or_default().insert(file_entry.file_id());
         }
-        &self.entries[idx]
+    }
+
+    // removes the entry at idx from every map, but leaves it in self.entries
+    fn unindex_entry(&mut self, idx: usize) {
+        fn remove_from<K: Hash + Eq, V: Hash + Eq>(map: &mut HashMap<K, HashSet<V>>, key: &K, value: &V) {
+            if let Some(values) = map.get_mut(key) {
+                values.remove(value);
+                // empty sets would make add_file() think there are files of this size
+                if values.is_empty() {
+                    map.remove(key);
+                }
+            }
+        }
+
+        let file_entry = self.entries[idx].clone();
+        let file_id = file_entry.file_id();
+        self.by_relative_path.remove(&file_entry.relative_path);
+        remove_from(&mut self.by_size, &file_entry.stat_size, &idx);
+        remove_from(&mut self.by_inode, &file_id, &idx);
+        if let Some(hash) = file_entry.fast_hash {
+            remove_from(&mut self.by_hash, &hash, &idx);
+        }
+
+        // other paths can still be links to the same inode, in which case it stays in the inode maps
+        let remaining_links: Vec<(u64, Option<u128>)> = self.by_inode.get(&file_id)
+            .map(|idxs| idxs.iter().map(|&i| (self.entries[i].stat_size, self.entries[i].fast_hash)).collect())
+            .unwrap_or_default();
+        if !remaining_links.iter().any(|&(size, _)| size == file_entry.stat_size) {
+            remove_from(&mut self.inode_by_size, &file_entry.stat_size, &file_id);
+        }
+        if let Some(hash) = file_entry.fast_hash {
+            if !remaining_links.iter().any(|&(_, h)| h == Some(hash)) {
+                remove_from(&mut self.inode_by_hash, &hash, &file_id);
+            }
+        }
+    }
+
+    // an inode we have rows for turned out to be a different file now (it was modified or deleted
+    // and reused), so nothing those rows say about its contents can be trusted any more
+    fn forget_inode(&mut self, file_id: (u64, u64)) -> Vec<FileEntry> {
+        let paths: Vec<PathBuf> = self.by_inode.get(&file_id)
+            .map(|idxs| idxs.iter().map(|&i| self.entries[i].relative_path.clone()).collect())
+            .unwrap_or_default();
+        paths.iter()
+            .filter_map(|path| self.remove_entry(path))
+            .collect()
+    }
+
+    // files in reference folders and protected files can be linked to, but are never replaced
+    fn is_pinned<Fs: AbstractFs>(&self, fs: &Fs, relative_path: &Path) -> bool {
+        relative_path.is_absolute() || fs.is_protected(self.base_path.join(relative_path))
+    }
+
+    // gives every path of an edited group except the first its own copy of the file, and marks
+    // them so they don't get linked back together on the next run. Pinned paths are left alone,
+    // and if there are any, they keep the inode instead of the first path.
+    pub fn split_edited_group<Fs: AbstractFs>(&mut self, fs: &mut Fs, group: &EditedGroup) -> Result<Vec<&FileEntry>> {
+        self.held_back.retain(|e| !group.relative_paths.contains(&e.relative_path));
+        let keep = group.relative_paths.iter().position(|p| self.is_pinned(fs, p)).unwrap_or(0);
+        let mut split_paths = vec![];
+        for (i, relative_path) in group.relative_paths.iter().enumerate() {
+            if i == keep || self.is_pinned(fs, relative_path) {
+                continue;
+            }
+            let abs_path = self.base_path.join(relative_path);
+            let mut split_filename = relative_path.file_name()
+                .ok_or_else(|| Error::invariant(format!("{:?} has no file name", relative_path)))?
+                .to_owned();
+            split_filename.push(".split");
+            let split_abs_path = abs_path.with_file_name(split_filename);
+            fs.copy(&abs_path, &split_abs_path)?;
+            if let Err(e) = fs.rename(&split_abs_path, &abs_path) {
+                fs.remove_file(&split_abs_path)?;
+                return Err(e);
+            }
+
+            let mut entry = FileEntry::new(fs, &self.base_path, &abs_path)?;
+            entry.fast_hash = Some(hash_file(fs, &abs_path)?);
+            entry.keep_separate = true;
+            self.update_file_entry(&entry);
+            split_paths.push(relative_path);
+        }
+        let index: &Self = self;
+        Ok(split_paths.into_iter()
+            .filter_map(|p| index.get_by_relative_path(p))
+            .collect())
     }
 
     fn hard_link_and_insert<Fs: AbstractFs>(&mut self, fs: &mut Fs,
                                             existing_entry: &FileEntry,
                                             new_entry: &FileEntry,
     ) -> Result<&FileEntry> {
-        assert_eq!(new_entry.stat_size, existing_entry.stat_size);
-        assert_eq!(new_entry.fast_hash, existing_entry.fast_hash);
-        assert_ne!(new_entry.stat_inode, existing_entry.stat_inode, "error: tried to link files that are the same");
-        assert_ne!(new_entry.relative_path, existing_entry.relative_path, "error: tried to link file to itself");
+        let new_abs_path = new_entry.absolute_path(&self.base_path);
+        // pinned files are never changed, that includes their metadata
+        let replaced = if self.config.reconcile.keeps_everything() || self.is_pinned(fs, &existing_entry.relative_path) {
+            None
+        } else {
+            // if it's gone, linking fails anyway
+            fs.metadata(&new_abs_path).ok()
+        };
+        let dir = new_abs_path.parent().map(Path::to_path_buf);
+        let dir_modified = match dir {
+            Some(dir) if self.config.preserve_dir_times => {
+                let modified = fs.dir_modified(&dir)?;
+                Some((dir, modified))
+            }
+            _ => None,
+        };
+        let linked = self.replace_with_link(fs, existing_entry, new_entry)
+            .map(|e| e.relative_path.clone());
+        // even if linking failed, the backup file may have come and gone
+        if let Some((dir, modified)) = dir_modified {
+            let restored = fs.set_dir_modified(&dir, modified);
+            if linked.is_ok() {
+                restored?;
+            }
+        }
+        let relative_path = linked?;
+        if let Some(replaced) = replaced {
+            self.reconcile_metadata(fs, &relative_path, &replaced)?;
+        }
+        Ok(self.get_by_relative_path(&relative_path).unwrap())
+    }
 
-        let mut backup_filename = new_entry.relative_path.file_name().unwrap().to_owned();
+    // applies config.reconcile to the inode relative_path was just linked to, given what the file
+    // it replaced looked like
+    fn reconcile_metadata<Fs: AbstractFs>(&mut self, fs: &mut Fs, relative_path: &Path, replaced: &Metadata) -> Result<()> {
+        let abs_path = self.base_path.join(relative_path);
+        let linked_to = fs.metadata(&abs_path)?;
+        let changes = self.config.reconcile.changes(&linked_to, replaced);
+        if changes.is_empty() {
+            return Ok(());
+        }
+        for change in &changes {
+            match *change {
+                MetadataChange::Mtime(_, modified) => fs.set_modified(&abs_path, modified)?,
+                MetadataChange::Mode(_, mode) => fs.set_mode(&abs_path, mode)?,
+                MetadataChange::Owner(_, (uid, gid)) => fs.set_owner(&abs_path, uid, gid)?,
+            }
+            self.reconciled.push(relative_path, *change);
+        }
+
+        // every path to the inode sees the new mtime and ctime
+        let on_disk = fs.metadata(&abs_path)?;
+        let linked_paths: Vec<FileEntry> = self.by_inode.get(&(on_disk.device, on_disk.inode))
+            .map(|idxs| idxs.iter().map(|&i| self.entries[i].clone()).collect())
+            .unwrap_or_default();
+        for linked_entry in linked_paths {
+            self.update_file_entry(&FileEntry {
+                stat_modified: on_disk.modified,
+                stat_changed: on_disk.changed,
+                ..linked_entry
+            });
+        }
+        Ok(())
+    }
+
+    fn replace_with_link<Fs: AbstractFs>(&mut self, fs: &mut Fs,
+                                         existing_entry: &FileEntry,
+                                         new_entry: &FileEntry,
+    ) -> Result<&FileEntry> {
+        if (new_entry.stat_size, new_entry.fast_hash) != (existing_entry.stat_size, existing_entry.fast_hash) {
+            return Err(Error::invariant(format!(
+                "tried to link {:?} to {:?} but their sizes or hashes differ",
+                new_entry.relative_path, existing_entry.relative_path)));
+        }
+        if new_entry.file_id() == existing_entry.file_id() {
+            return Err(Error::invariant(format!(
+                "tried to link {:?} to {:?} but they are already the same file",
+                new_entry.relative_path, existing_entry.relative_path)));
+        }
+        if new_entry.relative_path == existing_entry.relative_path {
+            return Err(Error::invariant(format!("tried to link {:?} to itself", new_entry.relative_path)));
+        }
+        if new_entry.is_reference() {
+            return Err(Error::invariant(format!(
+                "tried to replace {:?} with a link, but it is in a reference folder", new_entry.relative_path)));
+        }
+        if new_entry.keep_separate || existing_entry.keep_separate {
+            return Err(Error::invariant(format!(
+                "tried to link {:?} to {:?} but one of them was split on purpose",
+                new_entry.relative_path, existing_entry.relative_path)));
+        }
+
+        // either file could have been written to since they were compared, so right before anything
+        // gets replaced, make sure both are still exactly what was compared
+        for entry in [existing_entry, new_entry].iter() {
+            let on_disk = entry.stat_again(fs, &self.base_path)?;
+            if !entry.same_identity(&on_disk) || (entry.stat_size, entry.stat_modified) != (on_disk.stat_size, on_disk.stat_modified) {
+                if entry.relative_path == existing_entry.relative_path {
+                    // what the index says about it isn't true any more
+                    self.remove_entry(&existing_entry.relative_path);
+                    self.retry_queue.push(existing_entry.relative_path.clone());
+                }
+                self.retry_queue.push(new_entry.relative_path.clone());
+                return Err(Error::changed_during_scan(
+                    entry.absolute_path(&self.base_path), "changed after it was compared"));
+            }
+        }
+
+        let new_abs_path = new_entry.absolute_path(&self.base_path);
+        let mut backup_filename = new_entry.relative_path.file_name()
+            .ok_or_else(|| Error::invariant(format!("{:?} has no file name", new_entry.relative_path)))?
+            .to_owned();
         backup_filename.push(".backup");
-        let backup_abs_path = new_entry.absolute_path(&self.base_path).with_file_name(backup_filename);
-        fs.rename(new_entry.absolute_path(&self.base_path), &backup_abs_path)?;
-        fs.hard_link(existing_entry.absolute_path(&self.base_path), new_entry.absolute_path(&self.base_path))?;
-        // TODO: rename the file back, if hard link fails
-
-        let mut checked_new_entry = FileEntry::new(fs, &self.base_path, &new_entry.absolute_path(&self.base_path))?;
-        checked_new_entry.fast_hash = new_entry.fast_hash;
-        assert_eq!(
-            (checked_new_entry.fast_hash, checked_new_entry.stat_size, checked_new_entry.stat_inode),
-            (existing_entry.fast_hash, existing_entry.stat_size, existing_entry.stat_inode),
-            "fatal error linking {} to {}",
-            existing_entry.relative_path.to_string_lossy(), new_entry.relative_path.to_string_lossy());
+        let backup_abs_path = new_abs_path.with_file_name(backup_filename);
+        fs.rename(&new_abs_path, &backup_abs_path)?;
+        if let Err(e) = fs.hard_link(existing_entry.absolute_path(&self.base_path), &new_abs_path) {
+            fs.rename(&backup_abs_path, &new_abs_path)?;
+            return Err(e);
+        }
+
+        let checked_new_entry = FileEntry::new(fs, &self.base_path, &new_abs_path)
+            .and_then(|mut checked_new_entry| {
+                checked_new_entry.fast_hash = new_entry.fast_hash;
+                checked_new_entry.digest = existing_entry.digest.clone().or_else(|| new_entry.digest.clone());
+                if (checked_new_entry.fast_hash, checked_new_entry.stat_size, checked_new_entry.file_id())
+                    != (existing_entry.fast_hash, existing_entry.stat_size, existing_entry.file_id()) {
+                    return Err(Error::changed_during_scan(
+                        existing_entry.absolute_path(&self.base_path),
+                        "link target no longer matches the index"));
+                }
+                Ok(checked_new_entry)
+            });
+        let checked_new_entry = match checked_new_entry {
+            Ok(e) => e,
+            Err(e) => {
+                // put the original file back where it was
+                fs.remove_file(&new_abs_path)?;
+                fs.rename(&backup_abs_path, &new_abs_path)?;
+                return Err(e);
+            }
+        };
 
         fs.remove_file(&backup_abs_path)?;
+
+        // and the old inode lost a link, which bumped its ctime, if other paths still point at it
+        let old_links: Vec<FileEntry> = self.by_inode.get(&new_entry.file_id())
+            .map(|idxs| idxs.iter().map(|&i| self.entries[i].clone()).collect())
+            .unwrap_or_default();
+        for old_link in old_links.into_iter().filter(|e| e.relative_path != new_entry.relative_path) {
+            if let Ok(on_disk) = old_link.stat_again(fs, &self.base_path) {
+                if on_disk.file_id() == old_link.file_id() {
+                    self.update_file_entry(&FileEntry { stat_changed: on_disk.stat_changed, ..old_link });
+                }
+            }
+        }
+
+        // adding a link bumped the ctime of the inode, so every other path to it needs to know
+        let linked_paths: Vec<FileEntry> = self.by_inode.get(&checked_new_entry.file_id())
+            .map(|idxs| idxs.iter().map(|&i| self.entries[i].clone()).collect())
+            .unwrap_or_default();
+        for linked_entry in linked_paths {
+            self.update_file_entry(&FileEntry {
+                stat_changed: checked_new_entry.stat_changed,
+                ..linked_entry
+            });
+        }
         Ok(self.update_file_entry(&checked_new_entry))
     }
 
+    // links new_entry to existing_entry. Pinned files are never replaced though, so if new_entry
+    // is one, the paths of existing_entry get replaced by links to it instead, unless one of them
+    // is pinned too, then both are left as they are.
+    fn link_duplicate<Fs: AbstractFs>(&mut self, fs: &mut Fs,
+                                      existing_entry: &FileEntry,
+                                      new_entry: &FileEntry,
+    ) -> Result<&FileEntry> {
+        if !self.is_pinned(fs, &new_entry.relative_path) {
+            return self.hard_link_and_insert(fs, existing_entry, new_entry);
+        }
+        let pinned_path = new_entry.relative_path.clone();
+        self.update_file_entry(new_entry);
+        let mut linked_paths: Vec<PathBuf> = self.by_inode.get(&existing_entry.file_id())
+            .map(|idxs| idxs.iter().map(|&i| self.entries[i].relative_path.clone()).collect())
+            .unwrap_or_default();
+        linked_paths.sort();
+        if !linked_paths.iter().any(|p| self.is_pinned(fs, p)) {
+            for relative_path in linked_paths {
+                let pinned_entry = self.get_by_relative_path(&pinned_path).unwrap().clone();
+                let linked_entry = FileEntry {
+                    fast_hash: pinned_entry.fast_hash,
+                    ..self.get_by_relative_path(&relative_path).unwrap().clone()
+                };
+                self.hard_link_and_insert(fs, &pinned_entry, &linked_entry)?;
+            }
+        }
+        Ok(self.get_by_relative_path(&pinned_path).unwrap())
+    }
+
     pub fn add_file<Fs: AbstractFs, P: AsRef<Path>>(&mut self, fs: &mut Fs, path: P) -> Result<&FileEntry> {
-        let mut new_entry = FileEntry::new(fs, &self.base_path, path)?;
+        let new_entry = FileEntry::new(fs, &self.base_path, path)?;
+        self.add_entry(fs, new_entry)
+    }
+
+    // a file outside the base path, that files in it can be linked to
+    pub fn add_reference_file<Fs: AbstractFs, P: AsRef<Path>>(&mut self, fs: &mut Fs, path: P) -> Result<&FileEntry> {
+        let new_entry = FileEntry::new_reference(fs, path)?;
+        self.add_entry(fs, new_entry)
+    }
+
+    fn add_entry<Fs: AbstractFs>(&mut self, fs: &mut Fs, mut new_entry: FileEntry) -> Result<&FileEntry> {
         if let Some(existing_entry) = self.get_by_relative_path(&new_entry.relative_path) {
-            assert!(new_entry.eq_except_hash(existing_entry));
+            if !new_entry.eq_except_hash(existing_entry) {
+                // what the index says about it isn't true any more
+                self.remove_entry(&new_entry.relative_path);
+                self.retry_queue.push(new_entry.relative_path.clone());
+                return Err(Error::changed_during_scan(
+                    new_entry.absolute_path(&self.base_path), "differs from its index entry"));
+            }
             return Ok(self.get_by_relative_path(&new_entry.relative_path).unwrap());
         }
+        if let Some(cutoff) = self.config.settle_cutoff {
+            if new_entry.stat_modified > cutoff || new_entry.stat_changed > cutoff {
+                return Err(Error::unsettled(new_entry.absolute_path(&self.base_path)));
+            }
+        }
 
-        if self.by_inode.contains_key(&new_entry.stat_inode) {
-            // this file is already deduplicated into this index
-            return Ok(self.update_file_entry(&new_entry));
+        if let Some(moved) = self.stale.note_new_path(&new_entry) {
+            // same file under a new name, so there is no need to hash it again
+            new_entry.fast_hash = moved.entry.fast_hash;
+            new_entry.keep_separate = moved.entry.keep_separate;
+            new_entry.digest = moved.entry.digest.clone();
+        }
+        if new_entry.fast_hash.is_none() {
+            new_entry.fast_hash = self.cached_hash(fs, &new_entry);
+        }
+
+        if let Some(idxs) = self.by_inode.get(&new_entry.file_id()) {
+            let linked_entry = idxs.iter()
+                .map(|&i| &self.entries[i])
+                .find(|e| e.same_identity(&new_entry));
+            if let Some(linked_entry) = linked_entry {
+                // this file is already deduplicated into this index
+                new_entry.fast_hash = linked_entry.fast_hash;
+                new_entry.digest = linked_entry.digest.clone();
+                if new_entry.fast_hash.is_none() {
+                    // the other paths were unique in size until now, so they were never hashed
+                    new_entry.fast_hash = Some(hash_file(fs, &new_entry.absolute_path(&self.base_path))?);
+                    let linked_entries: Vec<FileEntry> = idxs.iter().map(|&i| self.entries[i].clone()).collect();
+                    self.check_unchanged(fs, &new_entry)?;
+                    new_entry = self.cache_hash(fs, &new_entry)?;
+                    for linked_entry in linked_entries {
+                        self.update_file_entry(&FileEntry {
+                            fast_hash: new_entry.fast_hash,
+                            stat_changed: new_entry.stat_changed,
+                            ..linked_entry
+                        });
+                    }
+                }
+                return Ok(self.update_file_entry(&new_entry));
+            }
+            // same inode number but a different file, so it has to go through the full comparison
+            self.forget_inode(new_entry.file_id());
         }
 
         if !self.by_size.contains_key(&new_entry.stat_size) {
@@ -284,59 +1199,159 @@ impl FilesIndex {
             return Ok(self.update_file_entry(&new_entry));
         }
 
-        // safe to unwrap because we checked the key is there above
-        let potential_dupes = self.by_size.get(&new_entry.stat_size).unwrap();
+        let potential_dupes = self.potential_dupes(&new_entry);
 
         if potential_dupes.len() == 1 {
-            let &existing_entry_idx = potential_dupes.iter().next().unwrap();
-            let existing_entry = self.entries[existing_entry_idx].clone();
-            match self.compare_files(fs, &existing_entry, &new_entry, false)? {
+            let existing_entry_idx = potential_dupes[0];
+            let mut existing_entry = self.entries[existing_entry_idx].clone();
+            if existing_entry.fast_hash.is_none() {
+                existing_entry.fast_hash = self.cached_hash(fs, &existing_entry);
+            }
+            if let (Some(existing_hash), Some(new_hash)) = (existing_entry.fast_hash, new_entry.fast_hash) {
+                if existing_hash != new_hash {
+                    return Ok(self.update_file_entry(&new_entry));
+                }
+            }
+            let compared = self.compare_files(fs, &existing_entry, &new_entry, false)?;
+            self.check_unchanged(fs, &existing_entry)?;
+            self.check_unchanged(fs, &new_entry)?;
+            match compared {
                 (equal, Some((existing_entry_hash, new_entry_hash))) => {
-                    let updated_existing_entry = FileEntry {
+                    let mut updated_existing_entry = FileEntry {
                         fast_hash: Some(existing_entry_hash),
-                        ..existing_entry
+                        ..existing_entry.clone()
                     };
+                    if existing_entry.fast_hash.is_none() {
+                        updated_existing_entry = self.cache_hash(fs, &updated_existing_entry)?;
+                    }
                     self.update_file_entry(&updated_existing_entry);
+                    let hashed_here = new_entry.fast_hash.is_none();
                     new_entry.fast_hash = Some(new_entry_hash);
                     return if equal {
                         // they are equal, so this is a duplicate file
-                        Ok(self.hard_link_and_insert(fs, &updated_existing_entry, &new_entry)?)
+                        Ok(self.link_duplicate(fs, &updated_existing_entry, &new_entry)?)
                     } else {
                         // it's a non-duplicate, so just insert it
+                        if hashed_here {
+                            new_entry = self.cache_hash(fs, &new_entry)?;
+                        }
                         Ok(self.update_file_entry(&new_entry))
                     };
                 }
                 // we told self.compare_files() not to short-circuit, so it better not have short-circuited
-                (_, None) => { unreachable!(); }
+                (_, None) => {
+                    return Err(Error::invariant("compare_files() short-circuited when told not to"));
+                }
             }
         }
 
         // file is non-unique in length, so we will now hash the whole thing
-        new_entry.fast_hash = Some(hash_file(fs, &new_entry.absolute_path(&self.base_path))?);
-
-        // now compare by hash to insert
-        let potential_dupes = match self.by_size.get(&new_entry.stat_size) {
-            None => {
-                // no duplicates, so we will just insert
-                return Ok(self.update_file_entry(&new_entry));
-            }
-            Some(x) => x,
-        };
+        let hashed_here = new_entry.fast_hash.is_none();
+        if hashed_here {
+            new_entry.fast_hash = Some(hash_file(fs, &new_entry.absolute_path(&self.base_path))?);
+            self.check_unchanged(fs, &new_entry)?;
+        }
 
         // from now on, we don't need to hash anything (so we can always short-circuit when we insert
-        for &idx in potential_dupes {
+        for idx in potential_dupes {
             // must clone this so we don't borrow self
             let existing_entry = self.entries[idx].clone();
+            if existing_entry.fast_hash.is_some() && existing_entry.fast_hash != new_entry.fast_hash {
+                continue;
+            }
             match self.compare_files(fs, &existing_entry, &new_entry, true)? {
                 (false, _) => continue,
-                (true, _) =>
-                // match found! we can now short-circuit
-                    return Ok(self.hard_link_and_insert(fs, &existing_entry, &new_entry)?),
+                (true, _) => {
+                    self.check_unchanged(fs, &existing_entry)?;
+                    self.check_unchanged(fs, &new_entry)?;
+                    // match found! we can now short-circuit
+                    return Ok(self.link_duplicate(fs, &existing_entry, &new_entry)?);
+                }
             }
         }
 
-        // if we get this far, then that means we didn't find any matches, and this file is unique
-        Ok(self.update_file_entry(&new_entry))
+        // if we get this far, then that means we didn't find any matches, and this file is unique
+        if hashed_here {
+            new_entry = self.cache_hash(fs, &new_entry)?;
+        }
+        Ok(self.update_file_entry(&new_entry))
+    }
+
+    // adds the files again that changed between being compared and being linked, now that they
+    // have hopefully settled. If one of them changes again, it is left for the next run.
+    pub fn retry_queued<Fs: AbstractFs>(&mut self, fs: &mut Fs) -> Vec<(PathBuf, Error)> {
+        let mut errors = vec![];
+        for relative_path in std::mem::take(&mut self.retry_queue) {
+            let abs_path = self.base_path.join(&relative_path);
+            let result = if relative_path.is_absolute() {
+                self.add_reference_file(fs, &abs_path).map(|_| ())
+            } else {
+                self.add_file(fs, &abs_path).map(|_| ())
+            };
+            if let Err(e) = result {
+                errors.push((abs_path, e));
+            }
+        }
+        self.unstable.append(&mut self.retry_queue);
+        errors
+    }
+
+    // a hash of a file that was written to while it was read is a hash of something that never
+    // existed, so the size and mtime have to be the same after hashing as they were before
+    fn check_unchanged<Fs: AbstractFs>(&mut self, fs: &Fs, entry: &FileEntry) -> Result<()> {
+        let on_disk = entry.stat_again(fs, &self.base_path)?;
+        if (on_disk.stat_size, on_disk.stat_modified) != (entry.stat_size, entry.stat_modified) {
+            self.unstable.push(entry.relative_path.clone());
+            return Err(Error::changed_during_scan(
+                entry.absolute_path(&self.base_path), "changed while it was being hashed"));
+        }
+        Ok(())
+    }
+
+    fn cached_hash<Fs: AbstractFs>(&self, fs: &Fs, entry: &FileEntry) -> Option<u128> {
+        if !self.config.xattr_cache {
+            return None;
+        }
+        read_cached_hash(fs, &entry.absolute_path(&self.base_path), entry.stat_size, entry.stat_modified)
+    }
+
+    // stores the hash of entry in its xattr, and returns it with the ctime that setting the xattr
+    // left behind. Not every filesystem has xattrs, so failing to set one isn't an error. Files in
+    // reference folders don't get one, since we don't write to them.
+    fn cache_hash<Fs: AbstractFs>(&self, fs: &mut Fs, entry: &FileEntry) -> Result<FileEntry> {
+        let hash = match entry.fast_hash {
+            Some(hash) if self.config.xattr_cache && !entry.is_reference() => hash,
+            _ => return Ok(entry.clone()),
+        };
+        let abs_path = entry.absolute_path(&self.base_path);
+        let changed = || Error::changed_during_scan(&abs_path, "changed while it was being hashed");
+        // setting the xattr bumps the ctime, so the ctime from hashing has to be checked before
+        let before = FileEntry::new(fs, &self.base_path, &abs_path)?;
+        if (before.stat_size, before.stat_modified, before.file_id(), before.stat_changed)
+            != (entry.stat_size, entry.stat_modified, entry.file_id(), entry.stat_changed) {
+            return Err(changed());
+        }
+        if write_cached_hash(fs, &abs_path, hash, entry.stat_size, entry.stat_modified).is_err() {
+            return Ok(entry.clone());
+        }
+        let after = FileEntry::new(fs, &self.base_path, &abs_path)?;
+        if (after.stat_size, after.stat_modified, after.file_id())
+            != (entry.stat_size, entry.stat_modified, entry.file_id()) {
+            return Err(changed());
+        }
+        Ok(FileEntry { stat_changed: after.stat_changed, ..entry.clone() })
+    }
+
+    // indexes of the files that new_entry could be linked to
+    // hard links can't cross filesystems, so only files on the same device count
+    fn potential_dupes(&self, new_entry: &FileEntry) -> Vec<usize> {
+        self.by_size.get(&new_entry.stat_size)
+            .map(|idxs| idxs.iter()
+                .cloned()
+                .filter(|&i| !self.entries[i].keep_separate)
+                .filter(|&i| self.entries[i].stat_device == new_entry.stat_device)
+                .collect())
+            .unwrap_or_default()
     }
 
     fn compare_files<Fs: AbstractFs>(&self, fs: &Fs, entry1: &FileEntry, entry2: &FileEntry, short_circuit: bool) -> Result<(bool, Option<(u128, u128)>)> {
@@ -380,10 +1395,19 @@ impl FilesIndex {
 
 #[cfg(test)]
 mod test {
-    use std::path::Path;
-
-    use crate::lib::files_index::FilesIndex;
-    use crate::lib::fs::TestFs;
+    use std::ffi::OsStr;
+    use std::io::Read;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    use crate::lib::audit::Change;
+    use crate::lib::check::check_entries;
+    use crate::lib::binary_index;
+    use crate::lib::files_index::{FilesIndex, IndexFormat};
+    use crate::lib::fs::{AbstractFs, GuardedFs, TestFs};
+    use crate::lib::reconcile::{MetadataChange, ModeRule, MtimeRule, OwnerRule, Policy};
+    use crate::lib::ErrorKind;
     use std::collections::HashSet;
 
     #[test]
@@ -408,7 +1432,7 @@ mod test {
         assert_eq!(index.get_by_relative_path(&"asdf2").unwrap(), &file_entries[1]);
         assert_eq!(index.get_by_relative_path(&"newfile").unwrap(), &file_entries[2]);
 
-        index.sanity_check();
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
     }
 
 
@@ -443,7 +1467,7 @@ mod test {
         assert_eq!(index.by_size.len(), 3);
         assert_eq!(index.by_size.get(&15).unwrap().len(), 2);
 
-        index.sanity_check();
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
     }
 
     #[test]
@@ -461,7 +1485,7 @@ mod test {
         index.add_file(&mut test_fs, f2.relative_path.as_path()).unwrap();
         let f1 = index.get_by_relative_path(&f1.relative_path).unwrap();
         let f2 = index.get_by_relative_path(&f2.relative_path).unwrap();
-        index.sanity_check();
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
         assert!(f1.fast_hash.is_some());
         assert!(f2.fast_hash.is_some());
         assert_eq!(f1.stat_inode, f2.stat_inode);
@@ -488,7 +1512,7 @@ mod test {
         let f1 = index.get_by_relative_path(&f1.relative_path).unwrap();
         let f2 = index.get_by_relative_path(&f2.relative_path).unwrap();
         let f3 = index.get_by_relative_path(&f3.relative_path).unwrap();
-        index.sanity_check();
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
         assert!(f1.fast_hash.is_some());
         assert!(f2.fast_hash.is_some());
         assert!(f3.fast_hash.is_some());
@@ -511,18 +1535,22 @@ mod test {
         index.add_file(&mut test_fs, f1.relative_path.as_path()).unwrap();
         index.add_file(&mut test_fs, f2.relative_path.as_path()).unwrap();
         index.add_file(&mut test_fs, f3.relative_path.as_path()).unwrap();
-        index.sanity_check();
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
 
+        index.created = SystemTime::UNIX_EPOCH;
         index.save(&mut test_fs).unwrap();
         let s = test_fs.get_file_data("/somefolder/.index_file.csv").unwrap();
         let s = std::str::from_utf8(s).unwrap();
-        assert_eq!(s, "relative_path,fast_hash,stat_size,stat_modified,stat_created,stat_inode
-test1,290827534275623791776536726795751555336,4,1970-01-01T00:00:00Z,1970-01-01T00:00:00Z,2
-test2,290827534275623791776536726795751555336,4,1970-01-01T00:00:00Z,1970-01-01T00:00:00Z,2
-test3,290827534275623791776536726795751555336,4,1970-01-01T00:00:00Z,1970-01-01T00:00:00Z,2
+        assert_eq!(s, "format,version,tool_version,fast_hash,base_path,created
+hardlink-deduplicator-index,2,0.1.0,murmur3-x64-128,/somefolder/,1970-01-01T00:00:00Z
+relative_path,fast_hash,stat_size,stat_modified,stat_created,stat_inode,stat_device,stat_changed,keep_separate,digest
+test1,290827534275623791776536726795751555336,4,1970-01-01T00:00:00Z,1970-01-01T00:00:00Z,2,1,1970-01-01T00:00:05Z,false,
+test2,290827534275623791776536726795751555336,4,1970-01-01T00:00:00Z,1970-01-01T00:00:00Z,2,1,1970-01-01T00:00:05Z,false,
+test3,290827534275623791776536726795751555336,4,1970-01-01T00:00:00Z,1970-01-01T00:00:00Z,2,1,1970-01-01T00:00:05Z,false,
 ");
-        let index = FilesIndex::for_base_path(&test_fs, base_path).unwrap();
-        index.sanity_check();
+        let index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+        assert_eq!(index.created, SystemTime::UNIX_EPOCH);
         let f1 = index.get_by_relative_path(&f1.relative_path).unwrap().clone();
         let f2 = index.get_by_relative_path(&f2.relative_path).unwrap().clone();
         let f3 = index.get_by_relative_path(&f3.relative_path).unwrap().clone();
@@ -553,8 +1581,8 @@ test2,290827534275623791776536726795751555336,4,1970-01-01T00:00:00Z,1970-01-01T
 test3,290827534275623791776536726795751555336,4,1970-01-01T00:00:00Z,1970-01-01T00:00:00Z,1970-01-01T00:00:00Z,2
 ");
 
-        let mut index = FilesIndex::for_base_path(&test_fs, base_path).unwrap();
-        index.sanity_check();
+        let mut index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
 
         let f1 = index.get_by_relative_path(&f1.relative_path).unwrap().clone();
         assert_eq!(f1.fast_hash, Some(290827534275623791776536726795751555336));
@@ -573,7 +1601,7 @@ test3,290827534275623791776536726795751555336,4,1970-01-01T00:00:00Z,1970-01-01T
         assert_eq!(f1.fast_hash, Some(290827534275623791776536726795751555336));
         assert_eq!(f1.fast_hash, f2.fast_hash);
         assert_eq!(f1.fast_hash, f3.fast_hash);
-        index.sanity_check();
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
     }
 
     #[test]
@@ -594,7 +1622,7 @@ test3,290827534275623791776536726795751555336,4,1970-01-01T00:00:00Z,1970-01-01T
             );
             index.add_file(&mut test_fs, f1.relative_path.as_path()).unwrap();
         }
-        index.sanity_check();
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
         assert_eq!(file_content.len(), index.by_inode.len());
         assert!(index.by_inode.len() < 199);
         assert!(index.by_size.len() < 199);
@@ -606,6 +1634,733 @@ test3,290827534275623791776536726795751555336,4,1970-01-01T00:00:00Z,1970-01-01T
         // TODO: find examples of hash collisions and test that
         assert_eq!(index.by_hash.len(), 29);
     }
-}
 
+    #[test]
+    pub fn test_file_changed_during_run() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+
+        let mut index = FilesIndex::new(base_path);
+
+        let f1 = test_fs.new_file_entry("/somefolder/test1", "asdf");
+        index.add_file(&mut test_fs, f1.relative_path.as_path()).unwrap();
+
+        // someone rewrites the file after we indexed it
+        test_fs.add_text_file("/somefolder/test1", "something else");
+        let e = index.add_file(&mut test_fs, f1.relative_path.as_path()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ChangedDuringScan);
+        // the old entry doesn't stay behind, and neither does its inode
+        assert!(index.get_by_relative_path(&f1.relative_path).is_none());
+        assert!(index.by_inode.is_empty() && index.inode_by_size.is_empty());
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+
+        // it gets added again as it is now
+        let errors = index.retry_queued(&mut test_fs);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(index.get_by_relative_path(&f1.relative_path).unwrap().stat_size, 14);
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+    }
+
+    #[test]
+    pub fn test_link_same_file_is_error() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+
+        let mut index = FilesIndex::new(base_path);
+
+        let f1 = test_fs.new_file_entry("/somefolder/test1", "asdf");
+        let e = index.hard_link_and_insert(&mut test_fs, &f1, &f1).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvariantViolation);
+        // nothing was touched
+        assert_eq!(test_fs.get_file_data("/somefolder/test1").unwrap(), b"asdf");
+        assert!(test_fs.get_file_data("/somefolder/test1.backup").is_err());
+    }
+
+    #[test]
+    pub fn test_sanity_check_reports_violations() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+
+        let mut index = FilesIndex::new(base_path);
+        let f1 = test_fs.new_file_entry("/somefolder/test1", "asdf");
+        let f2 = test_fs.new_file_entry("/somefolder/test2", "asdfasdf");
+        index.add_file(&mut test_fs, f1.relative_path.as_path()).unwrap();
+        index.add_file(&mut test_fs, f2.relative_path.as_path()).unwrap();
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+
+        // point the size index at the wrong file
+        index.by_size.get_mut(&4).unwrap().insert(1);
+        let violations = index.sanity_check();
+        assert!(!violations.is_empty());
+        assert!(violations.iter().any(|v| v.contains("size index has the wrong key")), "{:?}", violations);
+    }
+
+    #[test]
+    pub fn test_save_keeps_previous_index() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+
+        let mut index = FilesIndex::new(base_path);
+        let f1 = test_fs.new_file_entry("/somefolder/test1", "asdf");
+        index.add_file(&mut test_fs, f1.relative_path.as_path()).unwrap();
+        index.save(&mut test_fs).unwrap();
+        assert!(test_fs.get_file_data("/somefolder/.index_file.csv.prev").is_err());
+        assert!(test_fs.get_file_data("/somefolder/.index_file.csv.tmp").is_err());
+
+        let f2 = test_fs.new_file_entry("/somefolder/test2", "asdfasdf");
+        index.add_file(&mut test_fs, f2.relative_path.as_path()).unwrap();
+        index.save(&mut test_fs).unwrap();
+        let previous = test_fs.get_file_data("/somefolder/.index_file.csv.prev").unwrap().to_vec();
+        assert!(std::str::from_utf8(&previous).unwrap().contains("test1"));
+        assert!(!std::str::from_utf8(&previous).unwrap().contains("test2"));
+
+        // a broken index falls back to the previous one
+        test_fs.add_text_file("/somefolder/.index_file.csv", "relative_path,fast_hash\n\"broken");
+        let mut index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        assert_eq!(index.len(), 1);
+        assert!(index.get_by_relative_path(&f1.relative_path).is_some());
+        assert!(index.fallback.as_ref().unwrap().ends_with("using \"/somefolder/.index_file.csv.prev\" instead"));
+        index.release_lock(&mut test_fs).unwrap();
+
+        // but one that can't be looked at isn't treated as missing, saving would replace it
+        test_fs.unreadable.insert("/somefolder/.index_file.csv".to_owned());
+        let e = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    pub fn test_reused_inode() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+
+        let mut index = FilesIndex::new(base_path);
+        let f1 = test_fs.new_file_entry("/somefolder/test1", "asdf");
+        let f2 = test_fs.new_file_entry("/somefolder/test2", "qwer");
+        index.add_file(&mut test_fs, f1.relative_path.as_path()).unwrap();
+        index.add_file(&mut test_fs, f2.relative_path.as_path()).unwrap();
+
+        // test1 is deleted, and its inode ends up belonging to a copy of test2
+        test_fs.remove_file("/somefolder/test1").unwrap();
+        test_fs.add_file_with_inode("/somefolder/test3", b"qwer", f1.stat_inode);
+        let f3 = index.add_file(&mut test_fs, Path::new("test3")).unwrap().clone();
+
+        // it must not have been taken for a link of the old test1, it should be linked to test2
+        let f2 = index.get_by_relative_path(&f2.relative_path).unwrap();
+        assert_eq!(f3.file_id(), f2.file_id());
+        assert_eq!(f3.fast_hash, f2.fast_hash);
+        assert!(index.get_by_relative_path(&f1.relative_path).is_none());
+        assert_eq!(test_fs.get_file_data("/somefolder/test3").unwrap(), b"qwer");
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+    }
+
+    #[test]
+    pub fn test_remove_entry() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+
+        let mut index = FilesIndex::new(base_path);
+        for (name, content) in [("test1", "asdf"), ("test2", "asdf"), ("test3", "qwer"), ("test4", "z")].iter() {
+            let f = test_fs.new_file_entry(&format!("/somefolder/{}", name), content);
+            index.add_file(&mut test_fs, f.relative_path.as_path()).unwrap();
+        }
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+
+        let removed = index.remove_entry("test1").unwrap();
+        assert_eq!(removed.relative_path, Path::new("test1"));
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+        // test2 is still a link to the same inode
+        assert!(index.inode_by_size.get(&4).unwrap().contains(&removed.file_id()));
+
+        index.remove_entry("test2").unwrap();
+        index.remove_entry("test4").unwrap();
+        assert!(index.remove_entry("test4").is_none());
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+        assert_eq!(index.by_inode.len(), 1);
+        assert_eq!(index.inode_by_size.get(&4).unwrap().len(), 1);
+        assert!(index.get_by_relative_path(&"test3").is_some());
+    }
+
+    #[test]
+    pub fn test_edited_group() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+
+        let mut index = FilesIndex::new(base_path);
+        for (name, content) in [("test1", "asdf"), ("test2", "asdf"), ("test3", "asdf"), ("test4", "qwer")].iter() {
+            let f = test_fs.new_file_entry(&format!("/somefolder/{}", name), content);
+            index.add_file(&mut test_fs, f.relative_path.as_path()).unwrap();
+        }
+        index.save(&mut test_fs).unwrap();
+
+        // someone edits test2, which changes test1 and test3 too
+        test_fs.write_in_place("/somefolder/test2", b"asdfasdf");
+        let mut index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        assert_eq!(index.edited_groups.len(), 1);
+        let group = index.edited_groups[0].clone();
+        assert_eq!(group.relative_paths, vec![PathBuf::from("test1"), PathBuf::from("test2"), PathBuf::from("test3")]);
+        assert_eq!((group.old_size, group.new_size), (4, 8));
+        assert_eq!(index.len(), 1);
+        assert!(index.is_held_back("test1"));
+        assert!(index.stale.is_empty());
+
+        // it keeps getting reported until the group is split, but never as stale rows
+        index.save(&mut test_fs).unwrap();
+        let mut index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        assert_eq!(index.edited_groups, vec![group.clone()]);
+        assert!(index.stale.is_empty());
+
+        let split: Vec<_> = index.split_edited_group(&mut test_fs, &group).unwrap()
+            .into_iter()
+            .map(|e| e.relative_path.clone())
+            .collect();
+        assert_eq!(split, vec![PathBuf::from("test2"), PathBuf::from("test3")]);
+        assert!(test_fs.get_file_data("/somefolder/test2.split").is_err());
+        assert!(!index.is_held_back("test1"));
+        for name in ["test1", "test2", "test3"].iter() {
+            index.add_file(&mut test_fs, Path::new(name)).unwrap();
+        }
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+
+        // same contents, but the split paths don't get linked back together
+        let inodes: HashSet<u64> = ["test1", "test2", "test3"].iter()
+            .map(|name| index.get_by_relative_path(&name).unwrap().stat_inode)
+            .collect();
+        assert_eq!(inodes.len(), 3);
+        assert!(index.get_by_relative_path(&"test2").unwrap().keep_separate);
+        assert!(!index.get_by_relative_path(&"test1").unwrap().keep_separate);
+        assert_eq!(test_fs.get_file_data("/somefolder/test3").unwrap(), b"asdfasdf");
+    }
+
+    #[test]
+    pub fn test_existing_links() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+
+        // linked before we ever saw them
+        test_fs.add_file_with_inode("/somefolder/test1", b"asdf", 10);
+        test_fs.add_file_with_inode("/somefolder/test2", b"asdf", 10);
+        let mut index = FilesIndex::new(base_path);
+        index.add_file(&mut test_fs, Path::new("test1")).unwrap();
+        index.add_file(&mut test_fs, Path::new("test2")).unwrap();
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+        assert!(index.get_by_relative_path(&"test1").unwrap().fast_hash.is_some());
+    }
+
+    #[test]
+    pub fn test_stale_entries() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+
+        let mut index = FilesIndex::new(base_path);
+        for (name, content) in [("test1", "a"), ("test2", "bb"), ("test3", "ccc"), ("test4", "dddd")].iter() {
+            let f = test_fs.new_file_entry(&format!("/somefolder/{}", name), content);
+            index.add_file(&mut test_fs, f.relative_path.as_path()).unwrap();
+        }
+        index.save(&mut test_fs).unwrap();
+
+        test_fs.remove_file("/somefolder/test1").unwrap();
+        test_fs.add_text_file("/somefolder/test2", "bbbb");
+        test_fs.rename("/somefolder/test3", "/somefolder/test5").unwrap();
+        let mut index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.stale.summary(), "3 stale index entries: 2 deleted, 1 modified");
+
+        index.add_file(&mut test_fs, Path::new("test5")).unwrap();
+        let changes: Vec<_> = index.stale.iter()
+            .map(|s| (s.entry.relative_path.to_string_lossy().to_string(), s.change.clone()))
+            .collect();
+        assert_eq!(changes, vec![
+            ("test1".to_owned(), Change::Deleted),
+            ("test2".to_owned(), Change::Modified),
+            ("test3".to_owned(), Change::Moved(PathBuf::from("test5"))),
+        ]);
+    }
+
+    #[test]
+    pub fn test_edit_with_restored_mtime() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+
+        let mut index = FilesIndex::new(base_path);
+        // test2 has the same size, so test1 gets hashed
+        test_fs.new_file_entry("/somefolder/test2", "zxcv");
+        index.add_file(&mut test_fs, Path::new("test2")).unwrap();
+        let f1 = test_fs.new_file_entry("/somefolder/test1", "asdf");
+        let old_hash = index.add_file(&mut test_fs, Path::new("test1")).unwrap().fast_hash;
+        assert!(old_hash.is_some());
+        index.save(&mut test_fs).unwrap();
+
+        // same size, and the mtime is put back, so only the ctime gives it away
+        test_fs.write_in_place("/somefolder/test1", b"qwer");
+        test_fs.set_modified("/somefolder/test1", f1.stat_modified).unwrap();
+        let mut index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        assert_eq!(index.len(), 1);
+        let changes: Vec<_> = index.stale.iter().map(|s| s.change.clone()).collect();
+        assert_eq!(changes, vec![Change::Modified]);
+
+        let new_hash = index.add_file(&mut test_fs, Path::new("test1")).unwrap().fast_hash;
+        assert!(new_hash.is_some());
+        assert_ne!(new_hash, old_hash);
+    }
+
+    #[test]
+    pub fn test_moved_file_keeps_hash() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+
+        let mut index = FilesIndex::new(base_path);
+        for (name, content) in [("dir1/test1", "asdf"), ("dir1/test2", "qwer")].iter() {
+            let f = test_fs.new_file_entry(&format!("/somefolder/{}", name), content);
+            index.add_file(&mut test_fs, f.relative_path.as_path()).unwrap();
+        }
+        let hash = index.get_by_relative_path(&"dir1/test1").unwrap().fast_hash;
+        assert!(hash.is_some());
+        index.save(&mut test_fs).unwrap();
+
+        test_fs.rename("/somefolder/dir1/test1", "/somefolder/dir2/test1").unwrap();
+        let mut index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        let opens = test_fs.open_count();
+        index.add_file(&mut test_fs, Path::new("dir2/test1")).unwrap();
+        index.add_file(&mut test_fs, Path::new("dir1/test2")).unwrap();
+        // nothing had to be read again
+        assert_eq!(test_fs.open_count(), opens);
+        assert_eq!(index.get_by_relative_path(&"dir2/test1").unwrap().fast_hash, hash);
+        assert_eq!(index.stale.summary(), "1 stale index entries: 1 moved");
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+    }
+
+    #[test]
+    pub fn test_xattr_cache() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+
+        let mut index = FilesIndex::new(base_path);
+        index.config.xattr_cache = true;
+        for (name, content) in [("test1", "asdf"), ("test2", "qwer"), ("test3", "zxcv")].iter() {
+            let f = test_fs.new_file_entry(&format!("/somefolder/{}", name), content);
+            index.add_file(&mut test_fs, f.relative_path.as_path()).unwrap();
+        }
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+        index.save(&mut test_fs).unwrap();
+        // setting the xattrs changed the ctimes, which the index has to know about
+        let mut index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        assert_eq!(index.len(), 3);
+        index.release_lock(&mut test_fs).unwrap();
+
+        // the files went somewhere without their index
+        test_fs.remove_file("/somefolder/.index_file.csv").unwrap();
+        let mut index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        index.config.xattr_cache = true;
+        let opens = test_fs.open_count();
+        for name in ["test1", "test2", "test3"].iter() {
+            index.add_file(&mut test_fs, Path::new(name)).unwrap();
+        }
+        assert_eq!(test_fs.open_count(), opens);
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+        assert!(index.entries().iter().all(|e| e.fast_hash.is_some()));
+    }
+
+    #[test]
+    fn test_reference_files() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+        let inode = |test_fs: &TestFs, path: &str| test_fs.metadata(path).unwrap().inode;
+
+        let mut index = FilesIndex::new(base_path);
+        for (name, content) in [("a", "asdf"), ("b", "asdf"), ("c", "qwer")].iter() {
+            let f = test_fs.new_file_entry(&format!("/somefolder/{}", name), content);
+            index.add_file(&mut test_fs, f.relative_path.as_path()).unwrap();
+        }
+        assert_eq!(inode(&test_fs, "/somefolder/a"), inode(&test_fs, "/somefolder/b"));
+
+        // the reference file comes later, but the files in the folder still get linked to it
+        test_fs.add_text_file("/snapshot/a", "asdf");
+        test_fs.add_text_file("/snapshot/a2", "asdf");
+        let reference_inode = inode(&test_fs, "/snapshot/a");
+        let entry = index.add_reference_file(&mut test_fs, "/snapshot/a").unwrap();
+        assert!(entry.is_reference());
+        assert_eq!(entry.relative_path, Path::new("/snapshot/a"));
+        assert_eq!(inode(&test_fs, "/snapshot/a"), reference_inode);
+        assert_eq!(inode(&test_fs, "/somefolder/a"), reference_inode);
+        assert_eq!(inode(&test_fs, "/somefolder/b"), reference_inode);
+        // two reference files are never linked to each other
+        index.add_reference_file(&mut test_fs, "/snapshot/a2").unwrap();
+        assert_ne!(inode(&test_fs, "/snapshot/a2"), reference_inode);
+        assert_eq!(test_fs.get_file_data("/snapshot/a2").unwrap(), b"asdf");
+
+        test_fs.add_text_file("/snapshot/c", "qwer");
+        index.add_reference_file(&mut test_fs, "/snapshot/c").unwrap();
+        assert_eq!(inode(&test_fs, "/somefolder/c"), inode(&test_fs, "/snapshot/c"));
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+        // a2 isn't linked to anything, but it has the same contents
+        let duplicates = |path: &str| -> Vec<PathBuf> {
+            index.duplicates_of(&path).iter().map(|e| e.relative_path.clone()).collect()
+        };
+        assert_eq!(duplicates("/snapshot/a2"), vec![PathBuf::from("/snapshot/a"), PathBuf::from("a"), PathBuf::from("b")]);
+        assert_eq!(duplicates("c"), vec![PathBuf::from("/snapshot/c")]);
+        assert!(duplicates("missing").is_empty());
+
+        // and they survive a save and load
+        index.save(&mut test_fs).unwrap();
+        let mut index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        assert_eq!(index.len(), 6);
+        assert!(index.stale.is_empty());
+        index.retain_references(&[Path::new("/othersnapshot")]);
+        assert_eq!(index.len(), 3);
+        assert!(index.entries().iter().all(|e| !e.is_reference()));
+    }
+
+    #[test]
+    fn test_protected_files() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+        test_fs.add_text_file("/somefolder/a", "asdf");
+        test_fs.add_text_file("/somefolder/mounted/b", "asdf");
+        test_fs.add_text_file("/somefolder/c.db", "asdf");
+        let patterns = vec![glob::Pattern::new("/somefolder/mounted").unwrap(), glob::Pattern::new("/somefolder/*.db").unwrap()];
+        let mut fs = GuardedFs::new(test_fs, patterns);
+        let inode = |fs: &GuardedFs<TestFs>, path: &str| fs.metadata(path).unwrap().inode;
+
+        assert!(fs.is_protected("/somefolder/mounted/b"));
+        assert!(!fs.is_protected("/somefolder/a"));
+        let results = [
+            fs.remove_file("/somefolder/mounted/b"),
+            fs.rename("/somefolder/c.db", "/somefolder/d"),
+            fs.rename("/somefolder/a", "/somefolder/mounted/a"),
+            fs.hard_link("/somefolder/a", "/somefolder/mounted/a"),
+            fs.write_to_file("/somefolder/c.db", b"qwer"),
+        ];
+        for result in results.iter() {
+            assert_eq!(result.as_ref().unwrap_err().kind(), ErrorKind::Protected);
+        }
+
+        // a comes first, but the protected files are the ones that keep their inode
+        let protected_inode = inode(&fs, "/somefolder/mounted/b");
+        let mut index = FilesIndex::new(base_path);
+        for path in ["a", "mounted/b", "c.db"].iter() {
+            index.add_file(&mut fs, Path::new(path)).unwrap();
+        }
+        assert_eq!(inode(&fs, "/somefolder/a"), protected_inode);
+        assert_eq!(inode(&fs, "/somefolder/mounted/b"), protected_inode);
+        assert_ne!(inode(&fs, "/somefolder/c.db"), protected_inode);
+        assert_eq!(fs.inner.get_file_data("/somefolder/c.db").unwrap(), b"asdf");
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_unsettled_files() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+        test_fs.add_text_file("/somefolder/a", "asdf");
+        test_fs.add_text_file("/somefolder/b", "asdf");
+
+        let mut index = FilesIndex::new(base_path);
+        index.config.settle_cutoff = Some(test_fs.metadata("/somefolder/a").unwrap().changed);
+        index.add_file(&mut test_fs, Path::new("a")).unwrap();
+        // b was written after the cutoff
+        assert_eq!(index.add_file(&mut test_fs, Path::new("b")).unwrap_err().kind(), ErrorKind::Unsettled);
+        assert!(index.get_by_relative_path(&"b").is_none());
+
+        // and now it is written to while it is compared with a
+        index.config.settle_cutoff = None;
+        test_fs.write_during_next_read("/somefolder/b", b"asdfgh");
+        assert_eq!(index.add_file(&mut test_fs, Path::new("b")).unwrap_err().kind(), ErrorKind::ChangedDuringScan);
+        assert_eq!(index.unstable, vec![PathBuf::from("b")]);
+        assert!(index.get_by_relative_path(&"b").is_none());
+        assert_ne!(test_fs.metadata("/somefolder/a").unwrap().inode, test_fs.metadata("/somefolder/b").unwrap().inode);
+
+        // the next try sees the finished file
+        assert_eq!(index.add_file(&mut test_fs, Path::new("b")).unwrap().stat_size, 6);
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_preserve_dir_times() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+        test_fs.add_text_file("/somefolder/a", "asdf");
+        test_fs.add_text_file("/somefolder/sub/b", "asdf");
+        test_fs.add_text_file("/somefolder/sub/c", "asdf");
+        let sub_modified = test_fs.dir_modified("/somefolder/sub").unwrap();
+
+        let mut index = FilesIndex::new(base_path);
+        index.config.preserve_dir_times = true;
+        for name in ["a", "sub/b"].iter() {
+            index.add_file(&mut test_fs, Path::new(name)).unwrap();
+        }
+        assert_eq!(test_fs.metadata("/somefolder/a").unwrap().inode, test_fs.metadata("/somefolder/sub/b").unwrap().inode);
+        assert_eq!(test_fs.dir_modified("/somefolder/sub").unwrap(), sub_modified);
+
+        index.config.preserve_dir_times = false;
+        index.add_file(&mut test_fs, Path::new("sub/c")).unwrap();
+        assert_ne!(test_fs.dir_modified("/somefolder/sub").unwrap(), sub_modified);
+    }
+
+    #[test]
+    fn test_reconcile_metadata() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+        test_fs.add_text_file("/somefolder/a", "asdf");
+        test_fs.add_text_file("/somefolder/b", "asdf");
+        test_fs.add_text_file("/somefolder/c", "asdf");
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        test_fs.set_modified("/somefolder/a", SystemTime::UNIX_EPOCH + Duration::from_secs(100)).unwrap();
+        test_fs.set_modified("/somefolder/b", old).unwrap();
+        test_fs.set_modified("/somefolder/c", SystemTime::UNIX_EPOCH + Duration::from_secs(50)).unwrap();
+        test_fs.set_mode("/somefolder/b", 0o600).unwrap();
+
+        let mut index = FilesIndex::new(base_path);
+        index.config.reconcile = Policy { mtime: MtimeRule::Oldest, mode: ModeRule::MostRestrictive, owner: OwnerRule::Keep };
+        for name in ["a", "b", "c"].iter() {
+            index.add_file(&mut test_fs, Path::new(name)).unwrap();
+        }
+        let merged = test_fs.metadata("/somefolder/a").unwrap();
+        assert_eq!(merged.inode, test_fs.metadata("/somefolder/c").unwrap().inode);
+        assert_eq!((merged.modified, merged.mode), (old, 0o600));
+        // only linking b changed anything
+        let changes: Vec<_> = index.reconciled.iter().map(|r| (r.relative_path.to_str().unwrap(), r.change)).collect();
+        assert_eq!(changes, vec![
+            ("b", MetadataChange::Mtime(SystemTime::UNIX_EPOCH + Duration::from_secs(100), old)),
+            ("b", MetadataChange::Mode(0o644, 0o600)),
+        ]);
+        // and the index knows about the new mtime and ctime
+        assert!(index.entries().iter().all(|e| e.stat_modified == old && e.stat_changed == merged.changed));
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+
+        // a user's setuid, world writable copy of a root owned file
+        index.config.reconcile = Policy { mtime: MtimeRule::Keep, mode: ModeRule::LeastRestrictive, owner: OwnerRule::RootIfDifferent };
+        test_fs.add_text_file("/somefolder/root_file", "qwer");
+        test_fs.set_mode("/somefolder/root_file", 0o644).unwrap();
+        test_fs.add_text_file("/somefolder/users_copy", "qwer");
+        test_fs.set_owner("/somefolder/users_copy", 1000, 100).unwrap();
+        test_fs.set_mode("/somefolder/users_copy", 0o4777).unwrap();
+        // and a user's file that root made a copy of
+        test_fs.add_text_file("/somefolder/users_file", "zxcv");
+        test_fs.add_text_file("/somefolder/roots_copy", "zxcv");
+        test_fs.set_owner("/somefolder/users_file", 1000, 100).unwrap();
+        for name in ["root_file", "users_copy", "users_file", "roots_copy"].iter() {
+            index.add_file(&mut test_fs, Path::new(name)).unwrap();
+        }
+        let merged = test_fs.metadata("/somefolder/users_copy").unwrap();
+        assert_eq!((merged.mode, merged.uid, merged.gid), (0o755, 0, 0));
+        let merged = test_fs.metadata("/somefolder/roots_copy").unwrap();
+        assert_eq!((merged.mode, merged.uid, merged.gid), (0o644, 0, 0));
+        let changes: Vec<_> = index.reconciled.iter().skip(2).map(|r| (r.relative_path.to_str().unwrap(), r.change)).collect();
+        assert_eq!(changes, vec![
+            ("users_copy", MetadataChange::Mode(0o644, 0o755)),
+            ("roots_copy", MetadataChange::Owner((1000, 100), (0, 0))),
+        ]);
+    }
+
+    #[test]
+    fn test_other_devices() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+        test_fs.mounts.insert("/somefolder/mnt".to_owned(), 2);
+        test_fs.add_text_file("/somefolder/a", "asdf");
+        test_fs.add_text_file("/somefolder/mnt/b", "asdf");
+        test_fs.add_text_file("/somefolder/mnt/c", "asdf");
+
+        let mut index = FilesIndex::new(base_path);
+        for name in ["a", "mnt/b", "mnt/c"].iter() {
+            index.add_file(&mut test_fs, Path::new(name)).unwrap();
+        }
+        // b has nothing to be linked to on its own device, c is linked to b
+        let inode = |path| test_fs.metadata(path).unwrap().inode;
+        assert_ne!(inode("/somefolder/a"), inode("/somefolder/mnt/b"));
+        assert_eq!(inode("/somefolder/mnt/b"), inode("/somefolder/mnt/c"));
+        // and a is the only file of its size on its device, so it didn't need a hash
+        assert!(index.get_by_relative_path(&"a").unwrap().fast_hash.is_none());
+        assert!(check_entries(&test_fs, base_path, index.entries()).is_clean());
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_odd_file_names() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+        let names: Vec<PathBuf> = vec![
+            PathBuf::from("with,comma"),
+            PathBuf::from("with \"quotes\""),
+            PathBuf::from("new\nline\r"),
+            PathBuf::from("base64:looks encoded"),
+            PathBuf::from(OsStr::from_bytes(b"latin1 caf\xe9")),
+            PathBuf::from(OsStr::from_bytes(b"\xff\xfe/sub")),
+        ];
+        let mut index = FilesIndex::new(base_path);
+        for (i, name) in names.iter().enumerate() {
+            test_fs.add_text_file(base_path.join(name), if i % 2 == 0 { "asdf" } else { "qwerty" });
+            index.add_file(&mut test_fs, name).unwrap();
+        }
+        index.save(&mut test_fs).unwrap();
+        let csv = std::str::from_utf8(test_fs.get_file_data("/somefolder/.index_file.csv").unwrap()).unwrap();
+        assert!(csv.contains("\nbase64:bGF0aW4xIGNhZuk=,"));
+
+        let index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        assert!(index.stale.is_empty());
+        for name in &names {
+            assert_eq!(&index.get_by_relative_path(name).unwrap().relative_path, name);
+        }
+        assert_eq!(index.len(), names.len());
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+    }
 
+    #[test]
+    fn test_newer_index_format() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+        test_fs.add_text_file("/somefolder/test1", "asdf");
+        test_fs.add_text_file("/somefolder/.index_file.csv", "format,version,tool_version,fast_hash,base_path,created
+hardlink-deduplicator-index,3,9.0.0,murmur3-x64-128,/somefolder/,1970-01-01T00:00:00Z
+relative_path,fast_hash,stat_size,something_new
+test1,290827534275623791776536726795751555336,4,xyz
+");
+        // a readable previous index doesn't help, saving over the newer one would lose data
+        test_fs.add_text_file("/somefolder/.index_file.csv.prev", "");
+        let e = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnsupportedIndex);
+        assert!(e.to_string().contains("version 9.0.0"));
+
+        // there never was a version 0, so the header is broken rather than old
+        test_fs.add_text_file("/somefolder/.index_file.csv", "format,version,tool_version,fast_hash,base_path,created
+hardlink-deduplicator-index,0,0.1.0,murmur3-x64-128,/somefolder/,1970-01-01T00:00:00Z
+");
+        let e = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnsupportedIndex);
+
+        // a header and nothing else is an empty index
+        test_fs.add_text_file("/somefolder/.index_file.csv", "format,version,tool_version,fast_hash,base_path,created
+hardlink-deduplicator-index,2,0.1.0,murmur3-x64-128,/elsewhere/,1970-01-01T00:00:00Z
+");
+        let index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        assert_eq!(index.len(), 0);
+        assert_eq!(index.created, SystemTime::UNIX_EPOCH);
+    }
+
+    #[test]
+    fn test_binary_index() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+        let read_index = |test_fs: &TestFs| {
+            let mut buf = vec![];
+            test_fs.open("/somefolder/.index_file.csv").unwrap().read_to_end(&mut buf).unwrap();
+            buf
+        };
+        let mut index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        for (name, content) in [("a", "asdf"), ("sub/b", "asdf"), ("sub/c", "qwerty")].iter() {
+            test_fs.add_text_file(format!("/somefolder/{}", name), content);
+            index.add_file(&mut test_fs, Path::new(name)).unwrap();
+        }
+        index.save(&mut test_fs).unwrap();
+        let csv_entries = FilesIndex::load(&test_fs, base_path).unwrap().entries;
+
+        assert_eq!(FilesIndex::convert(&mut test_fs, base_path, IndexFormat::Binary, false).unwrap(), (3, None));
+        assert!(read_index(&test_fs).starts_with(binary_index::MAGIC));
+        let mut binary_entries = FilesIndex::load(&test_fs, base_path).unwrap().entries;
+        binary_entries.sort();
+        let mut sorted_csv_entries = csv_entries.clone();
+        sorted_csv_entries.sort();
+        assert_eq!(binary_entries, sorted_csv_entries);
+
+        // a run on a binary index saves it as a binary index again
+        let mut index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        assert_eq!(index.format, IndexFormat::Binary);
+        assert!(index.stale.is_empty());
+        test_fs.add_text_file("/somefolder/d", "qwerty");
+        index.add_file(&mut test_fs, Path::new("d")).unwrap();
+        index.save(&mut test_fs).unwrap();
+        assert!(read_index(&test_fs).starts_with(binary_index::MAGIC));
+        assert!(index.sanity_check().is_empty());
+
+        assert_eq!(FilesIndex::convert(&mut test_fs, base_path, IndexFormat::Csv, false).unwrap(), (4, None));
+        assert!(read_index(&test_fs).starts_with(b"format,version,"));
+        let index = FilesIndex::for_base_path(&mut test_fs, base_path, false).unwrap();
+        assert_eq!(index.format, IndexFormat::Csv);
+        assert_eq!(index.get_by_relative_path(&"d").unwrap().stat_inode, index.get_by_relative_path(&"sub/c").unwrap().stat_inode);
+    }
+
+    #[test]
+    fn test_changed_before_linking() {
+        let mut test_fs = TestFs::default();
+        let base_path = Path::new("/somefolder/");
+        test_fs.set_cwd(base_path);
+        let mut index = FilesIndex::new(base_path);
+        for (name, content) in [("a", "asdf"), ("b", "qwerty")].iter() {
+            let f = test_fs.new_file_entry(&format!("/somefolder/{}", name), content);
+            index.add_file(&mut test_fs, f.relative_path.as_path()).unwrap();
+        }
+
+        // c is written to after it was compared with a, but before it gets replaced
+        test_fs.add_text_file("/somefolder/c", "asdf");
+        test_fs.write_before_nth_stat("/somefolder/c", 3, b"asdfgh");
+        assert_eq!(index.add_file(&mut test_fs, Path::new("c")).unwrap_err().kind(), ErrorKind::ChangedDuringScan);
+        assert_ne!(test_fs.metadata("/somefolder/a").unwrap().inode, test_fs.metadata("/somefolder/c").unwrap().inode);
+        assert!(index.get_by_relative_path(&"c").is_none());
+
+        // and here it's b that changes, so its row can't be trusted either
+        test_fs.add_text_file("/somefolder/d", "qwerty");
+        test_fs.write_before_nth_stat("/somefolder/b", 2, b"zxcv");
+        assert_eq!(index.add_file(&mut test_fs, Path::new("d")).unwrap_err().kind(), ErrorKind::ChangedDuringScan);
+        assert!(index.get_by_relative_path(&"b").is_none());
+
+        let errors = index.retry_queued(&mut test_fs);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(index.unstable.is_empty());
+        assert_eq!(index.len(), 4);
+        assert_eq!(index.get_by_relative_path(&"c").unwrap().stat_size, 6);
+        assert_ne!(test_fs.metadata("/somefolder/b").unwrap().inode, test_fs.metadata("/somefolder/d").unwrap().inode);
+        assert_eq!(index.sanity_check(), Vec::<String>::new());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_goes_through_fs() {
+        use crate::lib::fs::{ReadOnlyFs, RealFs};
+        use crate::lib::files_index::{SQLITE_INDEX_FILE_NAME, SQLITE_JOURNAL_FILE_NAME};
+
+        let dir = std::env::temp_dir().join(format!("hardlink-deduplicator-sqlite-fs-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut index = FilesIndex::from_checked_entries(&dir, vec![]);
+        index.format = IndexFormat::Sqlite;
+
+        // a dry run or a protected index never writes the database
+        assert!(index.save(&mut ReadOnlyFs::default()).is_err());
+        let pattern = glob::Pattern::new(&dir.join("*").to_string_lossy()).unwrap();
+        assert_eq!(index.save(&mut GuardedFs::new(RealFs::new(&dir).unwrap(), vec![pattern])).unwrap_err().kind(), ErrorKind::Protected);
+        assert!(!dir.join(SQLITE_INDEX_FILE_NAME).exists());
+
+        let mut fs = RealFs::new(&dir).unwrap();
+        index.save(&mut fs).unwrap();
+        assert_eq!(FilesIndex::load(&fs, &dir).unwrap().format, IndexFormat::Sqlite);
+
+        // nor writes through a journal that was replaced by a symlink
+        std::os::unix::fs::symlink("/tmp/elsewhere", dir.join(SQLITE_JOURNAL_FILE_NAME)).unwrap();
+        assert!(FilesIndex::load(&fs, &dir).is_err());
+        assert!(index.save(&mut fs).is_err());
+        assert!(!Path::new("/tmp/elsewhere").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
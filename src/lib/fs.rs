@@ -1,10 +1,39 @@
 pub use std::io;
 pub use std::path::Path;
-use std::path::PathBuf;
-use std::time::SystemTime;
+use std::ffi::{CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Component, PathBuf};
+use std::time::{Duration, SystemTime};
 
 use super::Result;
 use super::Error;
+use super::ResultExt;
+
+// the parts of stat() that we care about
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Metadata {
+    pub size: u64,
+    pub modified: SystemTime,
+    pub accessed: SystemTime,
+    pub created: SystemTime,
+    // ctime: bumped by writes, but also by chmod, chown and adding or removing hard links
+    pub changed: SystemTime,
+    pub inode: u64,
+    pub device: u64,
+    // permission bits, including setuid, setgid and sticky
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+// an exclusive lock on a file, see AbstractFs::lock_file(). The kernel releases it when the file
+// is closed, so it doesn't outlive the process, however that ends.
+#[derive(Debug)]
+pub struct FileLock {
+    path: PathBuf,
+    file: Option<std::fs::File>,
+}
 
 pub trait AbstractFs {
     type File: std::io::Read;
@@ -12,60 +41,351 @@ pub trait AbstractFs {
     fn open<P: AsRef<Path>>(&self, path: P) -> Result<Self::File>;
     // fn open_writable<P: AsRef<Path>>(&mut self, path: P) -> Result<Self::WritableFile>;
     fn write_to_file<P: AsRef<Path>>(&mut self, path: P, buf: &[u8]) -> Result<()>;
+    // creates the file if needed
+    fn append_to_file<P: AsRef<Path>>(&mut self, path: P, buf: &[u8]) -> Result<()>;
 
     fn canonicalize<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf>;
-    // size, modified, accessed, created, inode
-    fn metadata<P: AsRef<Path>>(&self, path: P) -> Result<(u64, SystemTime, SystemTime, SystemTime, u64)>;
+    fn metadata<P: AsRef<Path>>(&self, path: P) -> Result<Metadata>;
 
     fn hard_link<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, src: P, dst: Q) -> Result<()>;
     fn remove_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()>;
     fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<()>;
+    // copies the contents into a new file with its own inode
+    fn copy<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, src: P, dst: Q) -> Result<()>;
+    // makes renames and new links in a directory durable
+    fn sync_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<()>;
+    // takes an exclusive flock() on the file, creating it if needed, and then replaces its contents.
+    // Ok(None) if someone else holds it already, which includes another lock_file() of the same
+    // file by this process.
+    fn lock_file<P: AsRef<Path>>(&mut self, path: P, contents: &[u8]) -> Result<Option<FileLock>>;
+    // empties the file again and releases the lock
+    fn unlock_file(&mut self, lock: FileLock) -> Result<()>;
+
+    // Ok(None) if the attribute isn't set. Setting one bumps the ctime, like any other change to
+    // the inode.
+    fn get_xattr<P: AsRef<Path>>(&self, path: P, name: &str) -> Result<Option<Vec<u8>>>;
+    fn set_xattr<P: AsRef<Path>>(&mut self, path: P, name: &str, value: &[u8]) -> Result<()>;
+
+    // the mtime of a directory, which changes whenever a file in it is created, renamed or removed
+    fn dir_modified<P: AsRef<Path>>(&self, path: P) -> Result<SystemTime>;
+    // leaves the atime alone
+    fn set_dir_modified<P: AsRef<Path>>(&mut self, path: P, modified: SystemTime) -> Result<()>;
+
+    // both bump the ctime
+    fn set_modified<P: AsRef<Path>>(&mut self, path: P, modified: SystemTime) -> Result<()>;
+    fn set_mode<P: AsRef<Path>>(&mut self, path: P, mode: u32) -> Result<()>;
+    fn set_owner<P: AsRef<Path>>(&mut self, path: P, uid: u32, gid: u32) -> Result<()>;
+
+    // files that may be read and linked to, but never changed, see GuardedFs
+    fn is_protected<P: AsRef<Path>>(&self, _path: P) -> bool {
+        false
+    }
+    // fails if a write to the path would be refused, for files that get written without going
+    // through fs, like the sqlite index
+    #[cfg(feature = "sqlite")]
+    fn check_writable<P: AsRef<Path>>(&self, _path: P) -> Result<()> {
+        Ok(())
+    }
 }
 
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
 
+// without a base path, every path is resolved the usual way, following symlinks
 #[derive(Debug, Default)]
-pub struct RealFs {}
+pub struct RealFs {
+    // the canonical base path, and the directory open there
+    base: Option<(PathBuf, std::fs::File)>,
+}
+
+impl RealFs {
+    pub fn new<P: AsRef<Path>>(base_path: P) -> Result<Self> {
+        let path = std::fs::canonicalize(&base_path).context("canonicalize", &base_path)?;
+        let dir = open_dir_following(&path).context("open", &path)?;
+        Ok(RealFs { base: Some((path, dir)) })
+    }
+
+    // the directory that path is in, the name of path in it, and the flag to open the name with.
+    // Paths in the base path are walked from the directory opened for it, see below. Anything
+    // else, like a changes log, a manifest or a reference folder, was given explicitly and is
+    // opened like any other program would, following symlinks.
+    fn open_parent(&self, path: &Path) -> io::Result<(std::fs::File, CString, libc::c_int)> {
+        let name = path.file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        match self.in_base(parent)? {
+            Some(relative) => Ok((self.open_in_base(&relative)?, c_name(name)?, libc::O_NOFOLLOW)),
+            None => Ok((open_dir_following(parent)?, c_name(name)?, 0)),
+        }
+    }
 
-impl RealFs {}
+    fn open_at(&self, path: &Path, flags: libc::c_int) -> io::Result<std::fs::File> {
+        let (dir, name, nofollow) = self.open_parent(path)?;
+        openat(&dir, &name, flags | nofollow)
+    }
+
+    fn open_dir(&self, path: &Path) -> io::Result<std::fs::File> {
+        match self.in_base(path)? {
+            Some(relative) => self.open_in_base(&relative),
+            None => open_dir_following(path),
+        }
+    }
+
+    // path relative to the base path, if it is in there
+    fn in_base(&self, path: &Path) -> io::Result<Option<PathBuf>> {
+        let base_path = match &self.base {
+            Some((base_path, _)) => base_path,
+            None => return Ok(None),
+        };
+        let path = std::env::current_dir()?.join(path);
+        Ok(path.strip_prefix(base_path).ok().map(|relative| relative.to_owned()))
+    }
+
+    fn open_in_base(&self, relative: &Path) -> io::Result<std::fs::File> {
+        let mut dir = self.base.as_ref().expect("only called for paths in the base path").1.try_clone()?;
+        for component in relative.components() {
+            let name = match component {
+                Component::CurDir => continue,
+                Component::Normal(name) => c_name(name)?,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "path leaves the base path")),
+            };
+            dir = openat(&dir, &name, libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW)?;
+        }
+        Ok(dir)
+    }
+}
 
 impl<'a> AbstractFs for RealFs {
     type File = std::fs::File;
     type WritableFile = std::fs::File;
     fn open<P: AsRef<Path>>(&self, path: P) -> Result<Self::File> {
-        Self::File::open(path).map_err(Into::into)
+        // non-blocking, so a fifo that was put in the place of a file can't make us hang
+        self.open_at(path.as_ref(), libc::O_RDONLY | libc::O_NONBLOCK).context("open", &path)
     }
     fn write_to_file<P: AsRef<Path>>(&mut self, path: P, buf: &[u8]) -> Result<()> {
-        let mut file = std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(path)?;
+        let mut file = self.open_at(path.as_ref(), libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC)
+            .context("create", &path)?;
+        use std::io::Write;
+        file.write_all(buf).context("write", &path)?;
+        file.sync_all().context("fsync", &path)
+    }
+    fn append_to_file<P: AsRef<Path>>(&mut self, path: P, buf: &[u8]) -> Result<()> {
         use std::io::Write;
-        file.write_all(buf).map_err(Into::into)
+        self.open_at(path.as_ref(), libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND)
+            .and_then(|mut file| file.write_all(buf))
+            .context("append", &path)
     }
     fn canonicalize<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
-        std::fs::canonicalize(path).map_err(Into::into)
+        std::fs::canonicalize(&path).context("canonicalize", &path)
     }
-    fn metadata<P: AsRef<Path>>(&self, path: P) -> Result<(u64, SystemTime, SystemTime, SystemTime, u64)> {
-        let m = std::fs::metadata(path)?;
-        if !m.is_file() {
-            return Err("path is not a file".into());
-        }
-        use std::os::linux::fs::MetadataExt;
-        Ok((m.len(), m.modified()?, m.accessed()?, m.created()?, m.st_ino()))
+    fn metadata<P: AsRef<Path>>(&self, path: P) -> Result<Metadata> {
+        // O_PATH doesn't need any permissions on the file itself, just like stat()
+        let m = self.open_at(path.as_ref(), libc::O_PATH)
+            .and_then(|file| file.metadata())
+            .context("stat", &path)?;
+        metadata_from_std(m).context("stat", &path)
     }
     fn hard_link<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, src: P, dst: Q) -> Result<()> {
-        std::fs::hard_link(src, dst).map_err(Into::into)
+        (|| {
+            let (src_dir, src_name, _) = self.open_parent(src.as_ref())?;
+            let (dst_dir, dst_name, _) = self.open_parent(dst.as_ref())?;
+            // without AT_SYMLINK_FOLLOW, a symlink gets linked as the symlink itself
+            cvt(unsafe {
+                libc::linkat(src_dir.as_raw_fd(), src_name.as_ptr(), dst_dir.as_raw_fd(), dst_name.as_ptr(), 0)
+            })
+        })().context2("hard link", &src, &dst)
     }
     fn remove_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
-        std::fs::remove_file(path).map_err(Into::into)
+        self.open_parent(path.as_ref())
+            .and_then(|(dir, name, _)| cvt(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) }))
+            .context("remove", &path)
     }
     fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<()> {
-        std::fs::rename(from, to).map_err(Into::into)
+        (|| {
+            let (from_dir, from_name, _) = self.open_parent(from.as_ref())?;
+            let (to_dir, to_name, _) = self.open_parent(to.as_ref())?;
+            cvt(unsafe {
+                libc::renameat(from_dir.as_raw_fd(), from_name.as_ptr(), to_dir.as_raw_fd(), to_name.as_ptr())
+            })
+        })().context2("rename", &from, &to)
+    }
+    fn copy<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, src: P, dst: Q) -> Result<()> {
+        (|| {
+            let mut src_file = self.open_at(src.as_ref(), libc::O_RDONLY | libc::O_NONBLOCK)?;
+            let mut dst_file = self.open_at(dst.as_ref(), libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC)?;
+            io::copy(&mut src_file, &mut dst_file)?;
+            dst_file.set_permissions(src_file.metadata()?.permissions())
+        })().context2("copy", &src, &dst)?;
+        self.open_at(dst.as_ref(), libc::O_RDONLY | libc::O_NONBLOCK)
+            .and_then(|file| file.sync_all())
+            .context("fsync", &dst)
+    }
+    fn sync_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.open_dir(path.as_ref())
+            .and_then(|dir| dir.sync_all())
+            .context("fsync", &path)
+    }
+    fn lock_file<P: AsRef<Path>>(&mut self, path: P, contents: &[u8]) -> Result<Option<FileLock>> {
+        use std::io::Write;
+        (|| {
+            // not truncated until we hold the lock, so the contents are always the holder's
+            let mut file = self.open_at(path.as_ref(), libc::O_RDWR | libc::O_CREAT)?;
+            if let Err(e) = cvt(unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) }) {
+                return match e.raw_os_error() {
+                    Some(libc::EWOULDBLOCK) => Ok(None),
+                    _ => Err(e),
+                };
+            }
+            file.set_len(0)?;
+            file.write_all(contents)?;
+            Ok(Some(FileLock { path: path.as_ref().to_owned(), file: Some(file) }))
+        })().context("lock", &path)
+    }
+    fn unlock_file(&mut self, lock: FileLock) -> Result<()> {
+        // the file stays, since removing it would let a run that already opened it lock the
+        // removed file while the next one creates a new file and locks that
+        if let Some(file) = &lock.file {
+            file.set_len(0).context("truncate", &lock.path)?;
+        }
+        Ok(())
+    }
+    fn get_xattr<P: AsRef<Path>>(&self, path: P, name: &str) -> Result<Option<Vec<u8>>> {
+        self.open_file_path(path.as_ref())
+            .and_then(|file| xattr::get(proc_fd_path(&file), name))
+            .context("get xattr", &path)
+    }
+    fn set_xattr<P: AsRef<Path>>(&mut self, path: P, name: &str, value: &[u8]) -> Result<()> {
+        self.open_file_path(path.as_ref())
+            .and_then(|file| xattr::set(proc_fd_path(&file), name, value))
+            .context("set xattr", &path)
+    }
+    fn dir_modified<P: AsRef<Path>>(&self, path: P) -> Result<SystemTime> {
+        self.open_dir(path.as_ref())
+            .and_then(|dir| dir.metadata())
+            .and_then(|m| m.modified())
+            .context("stat", &path)
+    }
+    fn set_dir_modified<P: AsRef<Path>>(&mut self, path: P, modified: SystemTime) -> Result<()> {
+        self.open_dir(path.as_ref())
+            .and_then(|dir| set_mtime(&dir, modified))
+            .context("set times", &path)
+    }
+    // none of these need to open the file for reading, so they also work on files without read
+    // permission
+    fn set_modified<P: AsRef<Path>>(&mut self, path: P, modified: SystemTime) -> Result<()> {
+        (|| {
+            let (dir, name, _) = self.open_parent(path.as_ref())?;
+            let times = mtime_only(modified)?;
+            cvt(unsafe { libc::utimensat(dir.as_raw_fd(), name.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) })
+        })().context("set times", &path)
+    }
+    fn set_mode<P: AsRef<Path>>(&mut self, path: P, mode: u32) -> Result<()> {
+        // fchmodat() can't leave a symlink alone, and fchmod() doesn't take an O_PATH descriptor
+        self.open_file_path(path.as_ref())
+            .and_then(|file| {
+                let proc_path = c_name(proc_fd_path(&file).as_os_str())?;
+                cvt(unsafe { libc::chmod(proc_path.as_ptr(), mode as libc::mode_t) })
+            })
+            .context("chmod", &path)
+    }
+    fn set_owner<P: AsRef<Path>>(&mut self, path: P, uid: u32, gid: u32) -> Result<()> {
+        self.open_parent(path.as_ref())
+            .and_then(|(dir, name, _)| cvt(unsafe {
+                libc::fchownat(dir.as_raw_fd(), name.as_ptr(), uid, gid, libc::AT_SYMLINK_NOFOLLOW)
+            }))
+            .context("chown", &path)
+    }
+}
+
+impl RealFs {
+    // a descriptor for the file itself, which needs no permissions on it, like stat(). Only
+    // regular files, since a symlink in its place would be followed through /proc.
+    fn open_file_path(&self, path: &Path) -> io::Result<std::fs::File> {
+        let file = self.open_at(path, libc::O_PATH)?;
+        if !file.metadata()?.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "path is not a file"));
+        }
+        Ok(file)
+    }
+}
+
+// a path for exactly the inode that file is open on, for calls that only take a path
+fn proc_fd_path(file: &std::fs::File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+// atime and mtime for utimensat() and futimens(), leaving the atime alone
+fn mtime_only(modified: SystemTime) -> io::Result<[libc::timespec; 2]> {
+    let since_epoch = modified.duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "time is before 1970"))?;
+    Ok([
+        libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+        libc::timespec { tv_sec: since_epoch.as_secs() as libc::time_t, tv_nsec: since_epoch.subsec_nanos() as libc::c_long },
+    ])
+}
+
+fn set_mtime(file: &std::fs::File, modified: SystemTime) -> io::Result<()> {
+    let times = mtime_only(modified)?;
+    cvt(unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) })
+}
+
+// RealFs runs as root over trees that other users can write to, so it never follows a symlink in
+// the base path: every path in there is opened one directory at a time from the base path with
+// O_NOFOLLOW, ".." is refused, and the operation itself is done relative to the directory that
+// ends up open (openat(), linkat(), renameat()...). If a directory gets swapped for a symlink part
+// way through a run, the operation fails instead of happening wherever the symlink points.
+
+fn cvt(ret: libc::c_int) -> io::Result<()> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn c_name(name: &OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"))
+}
+
+fn openat(dir: &std::fs::File, name: &CStr, flags: libc::c_int) -> io::Result<std::fs::File> {
+    let fd = unsafe {
+        libc::openat(dir.as_raw_fd(), name.as_ptr(), flags | libc::O_CLOEXEC, 0o666 as libc::c_uint)
+    };
+    cvt(fd)?;
+    Ok(unsafe { std::fs::File::from_raw_fd(fd) })
+}
+
+fn open_dir_following(path: &Path) -> io::Result<std::fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    // an empty parent is the current directory
+    let path = if path.as_os_str().is_empty() { Path::new(".") } else { path };
+    std::fs::OpenOptions::new().read(true).custom_flags(libc::O_DIRECTORY).open(path)
+}
+
+fn std_metadata(path: &Path) -> Result<Metadata> {
+    metadata_from_std(std::fs::metadata(path)?)
+}
+
+fn metadata_from_std(m: std::fs::Metadata) -> Result<Metadata> {
+    if !m.is_file() {
+        return Err("path is not a file".into());
     }
+    use std::os::linux::fs::MetadataExt;
+    // std doesn't expose ctime as a SystemTime
+    let changed = SystemTime::UNIX_EPOCH + Duration::new(m.st_ctime().max(0) as u64, m.st_ctime_nsec() as u32);
+    Ok(Metadata {
+        size: m.len(),
+        modified: m.modified()?,
+        accessed: m.accessed()?,
+        created: m.created()?,
+        changed,
+        inode: m.st_ino(),
+        device: m.st_dev(),
+        mode: m.st_mode() & 0o7777,
+        uid: m.st_uid(),
+        gid: m.st_gid(),
+    })
 }
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
@@ -78,30 +398,177 @@ impl AbstractFs for ReadOnlyFs {
     type File = std::fs::File;
     type WritableFile = std::fs::File;
     fn open<P: AsRef<Path>>(&self, path: P) -> Result<Self::File> {
-        Self::File::open(path).map_err(Into::into)
+        Self::File::open(&path).context("open", &path)
+    }
+    fn write_to_file<P: AsRef<Path>>(&mut self, path: P, _buf: &[u8]) -> Result<()> {
+        Err(Error::ReadOnlyFs()).context("write", &path)
     }
-    fn write_to_file<P: AsRef<Path>>(&mut self, _path: P, _buf: &[u8]) -> Result<()> {
-        Err(Error::ReadOnlyFs())
+    fn append_to_file<P: AsRef<Path>>(&mut self, path: P, _buf: &[u8]) -> Result<()> {
+        Err(Error::ReadOnlyFs()).context("append", &path)
     }
     fn canonicalize<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
-        std::fs::canonicalize(path).map_err(Into::into)
+        std::fs::canonicalize(&path).context("canonicalize", &path)
+    }
+    fn metadata<P: AsRef<Path>>(&self, path: P) -> Result<Metadata> {
+        std_metadata(path.as_ref()).context("stat", &path)
+    }
+    fn hard_link<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, src: P, dst: Q) -> Result<()> {
+        Err(Error::ReadOnlyFs()).context2("hard link", &src, &dst)
+    }
+    fn remove_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        Err(Error::ReadOnlyFs()).context("remove", &path)
+    }
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<()> {
+        Err(Error::ReadOnlyFs()).context2("rename", &from, &to)
     }
-    fn metadata<P: AsRef<Path>>(&self, path: P) -> Result<(u64, SystemTime, SystemTime, SystemTime, u64)> {
-        let m = std::fs::metadata(path)?;
-        if !m.is_file() {
-            return Err("path is not a file".into());
+    fn copy<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, src: P, dst: Q) -> Result<()> {
+        Err(Error::ReadOnlyFs()).context2("copy", &src, &dst)
+    }
+    fn sync_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        Err(Error::ReadOnlyFs()).context("fsync", &path)
+    }
+    fn lock_file<P: AsRef<Path>>(&mut self, path: P, _contents: &[u8]) -> Result<Option<FileLock>> {
+        Err(Error::ReadOnlyFs()).context("lock", &path)
+    }
+    fn unlock_file(&mut self, _lock: FileLock) -> Result<()> {
+        Ok(())
+    }
+    fn get_xattr<P: AsRef<Path>>(&self, path: P, name: &str) -> Result<Option<Vec<u8>>> {
+        xattr::get(&path, name).context("get xattr", &path)
+    }
+    fn set_xattr<P: AsRef<Path>>(&mut self, path: P, _name: &str, _value: &[u8]) -> Result<()> {
+        Err(Error::ReadOnlyFs()).context("set xattr", &path)
+    }
+    fn dir_modified<P: AsRef<Path>>(&self, path: P) -> Result<SystemTime> {
+        std::fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .context("stat", &path)
+    }
+    fn set_dir_modified<P: AsRef<Path>>(&mut self, path: P, _modified: SystemTime) -> Result<()> {
+        Err(Error::ReadOnlyFs()).context("set times", &path)
+    }
+    fn set_modified<P: AsRef<Path>>(&mut self, path: P, _modified: SystemTime) -> Result<()> {
+        Err(Error::ReadOnlyFs()).context("set times", &path)
+    }
+    fn set_mode<P: AsRef<Path>>(&mut self, path: P, _mode: u32) -> Result<()> {
+        Err(Error::ReadOnlyFs()).context("chmod", &path)
+    }
+    fn set_owner<P: AsRef<Path>>(&mut self, path: P, _uid: u32, _gid: u32) -> Result<()> {
+        Err(Error::ReadOnlyFs()).context("chown", &path)
+    }
+    #[cfg(feature = "sqlite")]
+    fn check_writable<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        Err(Error::ReadOnlyFs()).context("write", &path)
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// wraps another fs and refuses every change to a path that matches one of the protected patterns,
+// or is inside a folder that does. Those files can still be read, and be the source of a hard link.
+#[derive(Debug)]
+pub struct GuardedFs<Fs> {
+    pub inner: Fs,
+    protected: Vec<glob::Pattern>,
+}
+
+impl<Fs: AbstractFs> GuardedFs<Fs> {
+    // the patterns are matched against absolute paths, and `*` doesn't match a `/`
+    pub fn new(inner: Fs, protected: Vec<glob::Pattern>) -> Self {
+        Self { inner, protected }
+    }
+
+    fn check<P: AsRef<Path>>(&self, op: &'static str, path: P) -> Result<()> {
+        if self.is_protected(&path) {
+            return Err(Error::Protected()).context(op, &path);
         }
-        use std::os::linux::fs::MetadataExt;
-        Ok((m.len(), m.modified()?, m.accessed()?, m.created()?, m.st_ino()))
+        Ok(())
+    }
+}
+
+impl<Fs: AbstractFs> AbstractFs for GuardedFs<Fs> {
+    type File = Fs::File;
+    type WritableFile = Fs::WritableFile;
+    fn open<P: AsRef<Path>>(&self, path: P) -> Result<Self::File> {
+        self.inner.open(path)
+    }
+    fn write_to_file<P: AsRef<Path>>(&mut self, path: P, buf: &[u8]) -> Result<()> {
+        self.check("write", &path)?;
+        self.inner.write_to_file(path, buf)
+    }
+    fn append_to_file<P: AsRef<Path>>(&mut self, path: P, buf: &[u8]) -> Result<()> {
+        self.check("append", &path)?;
+        self.inner.append_to_file(path, buf)
+    }
+    fn canonicalize<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
+        self.inner.canonicalize(path)
+    }
+    fn metadata<P: AsRef<Path>>(&self, path: P) -> Result<Metadata> {
+        self.inner.metadata(path)
+    }
+    fn hard_link<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, src: P, dst: Q) -> Result<()> {
+        self.check("hard link", &dst)?;
+        self.inner.hard_link(src, dst)
+    }
+    fn remove_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.check("remove", &path)?;
+        self.inner.remove_file(path)
+    }
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<()> {
+        self.check("rename", &from)?;
+        self.check("rename", &to)?;
+        self.inner.rename(from, to)
     }
-    fn hard_link<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, _src: P, _dst: Q) -> Result<()> {
-        Err(Error::ReadOnlyFs())
+    fn copy<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, src: P, dst: Q) -> Result<()> {
+        self.check("copy", &dst)?;
+        self.inner.copy(src, dst)
     }
-    fn remove_file<P: AsRef<Path>>(&mut self, _path: P) -> Result<()> {
-        Err(Error::ReadOnlyFs())
+    fn sync_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.inner.sync_dir(path)
     }
-    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, _from: P, _to: Q) -> Result<()> {
-        Err(Error::ReadOnlyFs())
+    fn lock_file<P: AsRef<Path>>(&mut self, path: P, contents: &[u8]) -> Result<Option<FileLock>> {
+        self.check("lock", &path)?;
+        self.inner.lock_file(path, contents)
+    }
+    fn unlock_file(&mut self, lock: FileLock) -> Result<()> {
+        self.inner.unlock_file(lock)
+    }
+    fn get_xattr<P: AsRef<Path>>(&self, path: P, name: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.get_xattr(path, name)
+    }
+    fn set_xattr<P: AsRef<Path>>(&mut self, path: P, name: &str, value: &[u8]) -> Result<()> {
+        self.check("set xattr", &path)?;
+        self.inner.set_xattr(path, name, value)
+    }
+    fn dir_modified<P: AsRef<Path>>(&self, path: P) -> Result<SystemTime> {
+        self.inner.dir_modified(path)
+    }
+    fn set_dir_modified<P: AsRef<Path>>(&mut self, path: P, modified: SystemTime) -> Result<()> {
+        self.check("set times", &path)?;
+        self.inner.set_dir_modified(path, modified)
+    }
+    fn set_modified<P: AsRef<Path>>(&mut self, path: P, modified: SystemTime) -> Result<()> {
+        self.check("set times", &path)?;
+        self.inner.set_modified(path, modified)
+    }
+    fn set_mode<P: AsRef<Path>>(&mut self, path: P, mode: u32) -> Result<()> {
+        self.check("chmod", &path)?;
+        self.inner.set_mode(path, mode)
+    }
+    fn set_owner<P: AsRef<Path>>(&mut self, path: P, uid: u32, gid: u32) -> Result<()> {
+        self.check("chown", &path)?;
+        self.inner.set_owner(path, uid, gid)
+    }
+    fn is_protected<P: AsRef<Path>>(&self, path: P) -> bool {
+        let options = glob::MatchOptions { require_literal_separator: true, ..Default::default() };
+        path.as_ref().ancestors()
+            .any(|p| self.protected.iter().any(|pattern| pattern.matches_path_with(p, options)))
+    }
+    #[cfg(feature = "sqlite")]
+    fn check_writable<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.check("write", &path)?;
+        self.inner.check_writable(path)
     }
 }
 
@@ -110,20 +577,44 @@ impl AbstractFs for ReadOnlyFs {
 
 cfg_if::cfg_if! {
     if #[cfg(test)] {
-        use std::cell::UnsafeCell;
-        use std::ops::Deref;
-        use std::collections::HashMap;
+        use std::cell::{Cell, RefCell};
+        use std::collections::{HashMap, HashSet};
     }
 }
 
 #[cfg(test)]
 #[derive(Debug, Default)]
 pub struct TestFs {
-    filedata_: HashMap<String, Vec<u8>>,
-    inodes_: HashMap<String, u64>,
+    filedata_: HashMap<PathBuf, Vec<u8>>,
+    inodes_: HashMap<PathBuf, u64>,
+    // ctime by inode, every change just moves it forward by a second
+    changed_: HashMap<u64, SystemTime>,
+    // mtime by inode, only moved by write_in_place() and set_modified()
+    modified_: HashMap<u64, SystemTime>,
+    // permission bits by inode, 0o644 if not set
+    modes_: HashMap<u64, u32>,
+    // (uid, gid) by inode, root if not set
+    owners_: HashMap<u64, (u32, u32)>,
+    // extended attributes by inode
+    xattrs_: HashMap<u64, HashMap<String, Vec<u8>>>,
+    // directories don't exist otherwise, but their mtime moves whenever a file in them is
+    // created, renamed or removed
+    dirs_modified_: HashMap<PathBuf, SystemTime>,
+    clock_: u64,
     pub cwd: PathBuf,
-    // TODO: turn this into a function call log or something like that
-    count: UnsafeCell<i64>,
+    // files that someone holds a lock_file() on, this process or another one
+    pub locked: HashSet<String>,
+    // paths that metadata() reports permission denied for
+    pub unreadable: HashSet<String>,
+    // folders that are mount points, with their device numbers. Everything else is on device 1.
+    pub mounts: HashMap<String, u64>,
+    // number of open() calls, so tests can tell whether something was read again
+    opens: Cell<usize>,
+    // see write_during_next_read() and write_before_nth_stat()
+    writes_on_open_: RefCell<HashMap<PathBuf, Vec<u8>>>,
+    writes_on_stat_: RefCell<HashMap<PathBuf, (usize, Vec<u8>)>>,
+    // contents written by those, which win over filedata_ from then on
+    concurrent_writes_: RefCell<HashMap<PathBuf, Vec<u8>>>,
 }
 
 
@@ -138,15 +629,28 @@ impl TestFs {
     pub fn with_files(files: &[(&str, &str)]) -> TestFs {
         TestFs {
             filedata_: files.to_owned().iter()
-                .map(|(a, b)| (a.deref().to_owned(), b.to_owned().as_bytes().to_vec()))
-                .collect::<HashMap<String, Vec<u8>>>(),
+                .map(|(a, b)| (PathBuf::from(a), b.to_owned().as_bytes().to_vec()))
+                .collect::<HashMap<PathBuf, Vec<u8>>>(),
             // default to everything is a unique inode
             inodes_: files.to_owned().iter()
                 .enumerate()
-                .map(|(i, (a, _))| (a.deref().to_owned(), (i + 1) as u64))
-                .collect::<HashMap<String, u64>>(),
+                .map(|(i, (a, _))| (PathBuf::from(a), (i + 1) as u64))
+                .collect::<HashMap<PathBuf, u64>>(),
+            changed_: Default::default(),
+            modified_: Default::default(),
+            modes_: Default::default(),
+            owners_: Default::default(),
+            xattrs_: Default::default(),
+            dirs_modified_: Default::default(),
+            clock_: 0,
             cwd: PathBuf::from("/"),
-            count: UnsafeCell::new(0),
+            locked: Default::default(),
+            unreadable: Default::default(),
+            mounts: Default::default(),
+            opens: Cell::new(0),
+            writes_on_open_: Default::default(),
+            writes_on_stat_: Default::default(),
+            concurrent_writes_: Default::default(),
         }
     }
 
@@ -167,8 +671,12 @@ impl TestFs {
         println!("}}");
     }
 
+    pub fn open_count(&self) -> usize {
+        self.opens.get()
+    }
+
     pub fn get_file_data<P: AsRef<Path>>(&self, path: P) -> Result<&[u8]> {
-        match self.filedata_.get(&path_str(path)) {
+        match self.filedata_.get(path.as_ref()) {
             None => Err("File not found".into()),
             Some(s) => Ok(s),
         }
@@ -180,23 +688,80 @@ impl TestFs {
             .unwrap_or(1u64) + 1
     }
 
+    fn touch_inode(&mut self, inode: u64) {
+        self.clock_ += 1;
+        self.changed_.insert(inode, SystemTime::UNIX_EPOCH + Duration::from_secs(self.clock_));
+    }
+
+    fn device(&self, path: &Path) -> u64 {
+        path.ancestors()
+            .find_map(|dir| self.mounts.get(&path_str(dir)))
+            .cloned()
+            .unwrap_or(1)
+    }
+
+    fn touch_parent_dir<P: AsRef<Path>>(&mut self, path: P) {
+        // doesn't move the clock, so the ctimes tests expect stay the same
+        if let Some(parent) = path.as_ref().parent() {
+            self.dirs_modified_.insert(parent.to_owned(), SystemTime::UNIX_EPOCH + Duration::from_secs(self.clock_));
+        }
+    }
+
     pub fn set_cwd<P: AsRef<Path>>(&mut self, path: P) {
         self.cwd = path.as_ref().to_owned();
     }
 
-    pub fn add_text_file(&mut self, filename: &str, filedata: &str) {
-        self.filedata_.insert(filename.to_owned(), filedata.as_bytes().to_vec());
-        self.inodes_.insert(filename.to_owned(), self.next_inode());
+    pub fn add_text_file<P: AsRef<Path>>(&mut self, filename: P, filedata: &str) {
+        self.add_binary_file(filename, filedata.as_bytes());
+    }
+
+    pub fn add_binary_file<P: AsRef<Path>>(&mut self, filename: P, filedata: &[u8]) {
+        let inode = self.next_inode();
+        self.add_file_with_inode(filename, filedata, inode);
+    }
+
+    // lets tests reuse the inode number of a file that was deleted
+    pub fn add_file_with_inode<P: AsRef<Path>>(&mut self, filename: P, filedata: &[u8], inode: u64) {
+        self.filedata_.insert(filename.as_ref().to_owned(), filedata.to_vec());
+        self.inodes_.insert(filename.as_ref().to_owned(), inode);
+        self.touch_inode(inode);
+        self.touch_parent_dir(filename);
+    }
+
+    // writes into the existing inode, so every hard link of path sees the new data
+    pub fn write_in_place<P: AsRef<Path>>(&mut self, path: P, filedata: &[u8]) {
+        let inode = self.inodes_[path.as_ref()];
+        let links: Vec<PathBuf> = self.inodes_.iter()
+            .filter(|(_, &i)| i == inode)
+            .map(|(p, _)| p.clone())
+            .collect();
+        for link in links {
+            self.filedata_.insert(link, filedata.to_vec());
+        }
+        self.touch_inode(inode);
+        self.modified_.insert(inode, self.changed_[&inode]);
+    }
+
+    // like a writer that is still busy with the file: the next open() of path still reads the old
+    // contents, but right after it the file has the new ones, with a newer mtime
+    pub fn write_during_next_read<P: AsRef<Path>>(&mut self, path: P, filedata: &[u8]) {
+        self.writes_on_open_.borrow_mut().insert(path.as_ref().to_owned(), filedata.to_vec());
     }
 
-    pub fn add_binary_file(&mut self, filename: &str, filedata: &[u8]) {
-        self.filedata_.insert(filename.to_owned(), filedata.to_vec());
-        self.inodes_.insert(filename.to_owned(), self.next_inode());
+    // like another process writing to the file in between two of our looks at it: the n-th
+    // metadata() of path from now on, counting from 1, already sees the new contents
+    pub fn write_before_nth_stat<P: AsRef<Path>>(&mut self, path: P, n: usize, filedata: &[u8]) {
+        self.writes_on_stat_.borrow_mut().insert(path.as_ref().to_owned(), (n, filedata.to_vec()));
     }
 
-    pub fn new_file_entry(&mut self, path: &str, filedata: &str) -> super::file_entry::FileEntry {
-        self.add_text_file(path, filedata);
-        super::file_entry::FileEntry::new(self, &self.cwd, Path::new(path)).unwrap()
+    // swaps out the contents without touching any metadata, like bitrot would
+    pub fn corrupt<P: AsRef<Path>>(&mut self, path: P, filedata: &[u8]) {
+        self.filedata_.insert(path.as_ref().to_owned(), filedata.to_vec());
+    }
+
+    pub fn new_file_entry<P: AsRef<Path>>(&mut self, path: P, filedata: &str) -> super::file_entry::FileEntry {
+        self.add_text_file(&path, filedata);
+        super::file_entry::FileEntry::new(self, &self.cwd, path).unwrap()
     }
 }
 
@@ -206,21 +771,30 @@ impl AbstractFs for TestFs {
     type WritableFile = std::io::Cursor<&'static mut Vec<u8>>;
 
     fn open<P: AsRef<Path>>(&self, path: P) -> Result<Self::File> {
-        match self.filedata_.get(&path_str(path)) {
-            None => Err("File not found".into()),
-            Some(s) => Ok(std::io::Cursor::new(s.to_vec().into_boxed_slice())),
+        self.opens.set(self.opens.get() + 1);
+        let path = path.as_ref().to_owned();
+        let data = self.concurrent_writes_.borrow().get(&path).cloned()
+            .or_else(|| self.filedata_.get(&path).cloned())
+            .ok_or_else(|| Error::from("File not found"))?;
+        if let Some(new_data) = self.writes_on_open_.borrow_mut().remove(&path) {
+            self.concurrent_writes_.borrow_mut().insert(path, new_data);
         }
+        Ok(std::io::Cursor::new(data.into_boxed_slice()))
     }
 
     fn write_to_file<P: AsRef<Path>>(&mut self, path: P, buf: &[u8]) -> Result<()> {
-        self.add_binary_file(&path_str(&path), buf);
+        self.add_binary_file(&path, buf);
+        Ok(())
+    }
+
+    fn append_to_file<P: AsRef<Path>>(&mut self, path: P, buf: &[u8]) -> Result<()> {
+        let mut file_content = self.filedata_.get(path.as_ref()).cloned().unwrap_or_default();
+        file_content.extend_from_slice(buf);
+        self.add_binary_file(&path, &file_content);
         Ok(())
     }
 
     fn canonicalize<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
-        // unsafe {
-        //     *self.count.get() += 1;
-        // }
         // TODO what should we even do here?
         if path.as_ref().has_root() {
             Ok(path.as_ref().to_owned())
@@ -229,68 +803,268 @@ impl AbstractFs for TestFs {
         }
     }
 
-    // size, modified, accessed, created, inode
-    fn metadata<P: AsRef<Path>>(&self, path: P) -> Result<(u64, SystemTime, SystemTime, SystemTime, u64)> {
-        let path_str = path.as_ref().to_string_lossy();
-        println!("metadata({:?})", path_str);
-        let buf = self.filedata_.get(path_str.as_ref())
-            .ok_or_else(|| Error::from(format!("file {:?} not found", path_str)))?;
-        let inode = self.inodes_.get(path_str.as_ref()).ok_or_else(|| Error::from(format!("file {:?} not found", path_str)))?;
-        Ok((
-            buf.len() as u64,
-            // TODO: fix that
-            SystemTime::UNIX_EPOCH,
-            SystemTime::UNIX_EPOCH,
-            SystemTime::UNIX_EPOCH,
-            inode.clone(),
-        ))
+    fn metadata<P: AsRef<Path>>(&self, path: P) -> Result<Metadata> {
+        let path = path.as_ref();
+        println!("metadata({:?})", path);
+        if self.unreadable.contains(&path_str(path)) {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied)).context("stat", &path);
+        }
+        let mut writes_on_stat = self.writes_on_stat_.borrow_mut();
+        if let Some((n, _)) = writes_on_stat.get_mut(path) {
+            *n -= 1;
+            if *n == 0 {
+                let (_, new_data) = writes_on_stat.remove(path).unwrap();
+                self.concurrent_writes_.borrow_mut().insert(path.to_owned(), new_data);
+            }
+        }
+        let not_found = || Error::from(io::Error::from(io::ErrorKind::NotFound)).with_path("stat", &path);
+        let buf = self.filedata_.get(path).ok_or_else(not_found)?;
+        let inode = self.inodes_.get(path).ok_or_else(not_found)?;
+        let mut modified = self.modified_.get(inode).cloned().unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut changed = self.changed_.get(inode).cloned().unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut size = buf.len() as u64;
+        if let Some(new_data) = self.concurrent_writes_.borrow().get(path) {
+            size = new_data.len() as u64;
+            modified = SystemTime::UNIX_EPOCH + Duration::from_secs(self.clock_ + 1);
+            changed = modified;
+        }
+        Ok(Metadata {
+            size,
+            modified,
+            accessed: SystemTime::UNIX_EPOCH,
+            created: SystemTime::UNIX_EPOCH,
+            changed,
+            inode: *inode,
+            device: self.device(path.as_ref()),
+            mode: self.modes_.get(inode).cloned().unwrap_or(0o644),
+            uid: self.owners_.get(inode).map_or(0, |owner| owner.0),
+            gid: self.owners_.get(inode).map_or(0, |owner| owner.1),
+        })
     }
 
     fn hard_link<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, src: P, dst: Q) -> Result<()> {
         self.pretty_print();
-        println!("hard_link({:?},{:?})", &path_str(&src), &path_str(&dst));
-        if let Some(_) = self.filedata_.get(&path_str(&dst)) {
+        println!("hard_link({:?},{:?})", src.as_ref(), dst.as_ref());
+        if let Some(_) = self.filedata_.get(dst.as_ref()) {
             return Err("dst file exists!".into());
         }
-        let file_content = self.filedata_.get(&path_str(&src))
+        if self.device(src.as_ref()) != self.device(dst.as_ref()) {
+            return Err(io::Error::from_raw_os_error(libc::EXDEV)).context2("hard link", &src, &dst);
+        }
+        let file_content = self.filedata_.get(src.as_ref())
             .cloned()
             .ok_or_else(|| Error::from("file not found".to_owned()))?;
-        let inode = self.inodes_.get(&path_str(&src))
+        let inode = self.inodes_.get(src.as_ref())
             .cloned()
             .ok_or_else(|| Error::from("file not found".to_owned()))?;
-        self.filedata_.insert(path_str(&dst), file_content);
-        self.inodes_.insert(path_str(&dst), inode);
+        self.filedata_.insert(dst.as_ref().to_owned(), file_content);
+        self.inodes_.insert(dst.as_ref().to_owned(), inode);
+        self.touch_inode(inode);
+        self.touch_parent_dir(&dst);
         Ok(())
     }
 
     fn remove_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
-        self.inodes_.remove(&path_str(&path)).ok_or_else(|| Error::from("file not found"))?;
-        self.filedata_.remove(&path_str(&path)).ok_or_else(|| Error::from("file_not_found"))?;
+        self.inodes_.remove(path.as_ref()).ok_or_else(|| Error::from("file not found"))?;
+        self.filedata_.remove(path.as_ref()).ok_or_else(|| Error::from("file_not_found"))?;
+        self.touch_parent_dir(&path);
         Ok(())
     }
 
     fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<()> {
         // get the file that we're going to move
-        let file_content = self.filedata_.get(&path_str(&from))
+        let file_content = self.filedata_.get(from.as_ref())
             .cloned()
             .ok_or_else(|| Error::from("file not found".to_owned()))?;
-        let inode = self.inodes_.get(&path_str(&from))
+        let inode = self.inodes_.get(from.as_ref())
             .cloned()
             .ok_or_else(|| Error::from("file not found".to_owned()))?;
 
         // if an existing file exists, overwrite it
-        if let Some(_) = self.filedata_.get(&path_str(&to)) {
-            self.inodes_.remove(&path_str(&to)).ok_or_else(|| Error::from("file not found"))?;
-            self.filedata_.remove(&path_str(&to)).ok_or_else(|| Error::from("file_not_found"))?;
+        if let Some(_) = self.filedata_.get(to.as_ref()) {
+            self.inodes_.remove(to.as_ref()).ok_or_else(|| Error::from("file not found"))?;
+            self.filedata_.remove(to.as_ref()).ok_or_else(|| Error::from("file_not_found"))?;
         }
 
         // remove the old file
-        self.inodes_.remove(&path_str(&from)).ok_or_else(|| Error::from("file not found"))?;
-        self.filedata_.remove(&path_str(&from)).ok_or_else(|| Error::from("file_not_found"))?;
+        self.inodes_.remove(from.as_ref()).ok_or_else(|| Error::from("file not found"))?;
+        self.filedata_.remove(from.as_ref()).ok_or_else(|| Error::from("file_not_found"))?;
 
         // insert the new file
-        self.filedata_.insert(path_str(&to), file_content);
-        self.inodes_.insert(path_str(&to), inode);
+        self.filedata_.insert(to.as_ref().to_owned(), file_content);
+        self.inodes_.insert(to.as_ref().to_owned(), inode);
+        self.touch_parent_dir(&from);
+        self.touch_parent_dir(&to);
         Ok(())
     }
+
+    fn copy<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, src: P, dst: Q) -> Result<()> {
+        let file_content = self.filedata_.get(src.as_ref())
+            .cloned()
+            .ok_or_else(|| Error::from("file not found".to_owned()))?;
+        self.add_binary_file(&dst, &file_content);
+        Ok(())
+    }
+
+    fn sync_dir<P: AsRef<Path>>(&mut self, _path: P) -> Result<()> {
+        Ok(())
+    }
+
+    fn lock_file<P: AsRef<Path>>(&mut self, path: P, contents: &[u8]) -> Result<Option<FileLock>> {
+        if !self.locked.insert(path_str(&path)) {
+            return Ok(None);
+        }
+        self.write_to_file(&path, contents)?;
+        Ok(Some(FileLock { path: path.as_ref().to_owned(), file: None }))
+    }
+    fn unlock_file(&mut self, lock: FileLock) -> Result<()> {
+        self.write_to_file(&lock.path, b"")?;
+        self.locked.remove(&path_str(&lock.path));
+        Ok(())
+    }
+
+    fn get_xattr<P: AsRef<Path>>(&self, path: P, name: &str) -> Result<Option<Vec<u8>>> {
+        let inode = self.inodes_.get(path.as_ref())
+            .ok_or_else(|| Error::from("file not found".to_owned()))?;
+        Ok(self.xattrs_.get(inode).and_then(|xattrs| xattrs.get(name)).cloned())
+    }
+
+    fn set_xattr<P: AsRef<Path>>(&mut self, path: P, name: &str, value: &[u8]) -> Result<()> {
+        let inode = *self.inodes_.get(path.as_ref())
+            .ok_or_else(|| Error::from("file not found".to_owned()))?;
+        self.xattrs_.entry(inode).or_default().insert(name.to_owned(), value.to_vec());
+        self.touch_inode(inode);
+        Ok(())
+    }
+
+    fn dir_modified<P: AsRef<Path>>(&self, path: P) -> Result<SystemTime> {
+        Ok(self.dirs_modified_.get(path.as_ref()).cloned().unwrap_or(SystemTime::UNIX_EPOCH))
+    }
+
+    fn set_dir_modified<P: AsRef<Path>>(&mut self, path: P, modified: SystemTime) -> Result<()> {
+        self.dirs_modified_.insert(path.as_ref().to_owned(), modified);
+        Ok(())
+    }
+
+    fn set_modified<P: AsRef<Path>>(&mut self, path: P, modified: SystemTime) -> Result<()> {
+        let inode = *self.inodes_.get(path.as_ref()).ok_or_else(|| Error::from("file not found"))?;
+        self.modified_.insert(inode, modified);
+        self.touch_inode(inode);
+        Ok(())
+    }
+
+    fn set_mode<P: AsRef<Path>>(&mut self, path: P, mode: u32) -> Result<()> {
+        let inode = *self.inodes_.get(path.as_ref()).ok_or_else(|| Error::from("file not found"))?;
+        self.modes_.insert(inode, mode);
+        self.touch_inode(inode);
+        Ok(())
+    }
+
+    fn set_owner<P: AsRef<Path>>(&mut self, path: P, uid: u32, gid: u32) -> Result<()> {
+        let inode = *self.inodes_.get(path.as_ref()).ok_or_else(|| Error::from("file not found"))?;
+        self.owners_.insert(inode, (uid, gid));
+        self.touch_inode(inode);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::lib::fs::{AbstractFs, RealFs};
+
+    #[test]
+    fn test_real_fs_does_not_follow_symlinks() {
+        let dir = std::env::temp_dir().join(format!("hardlink-deduplicator-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("base/sub")).unwrap();
+        std::fs::create_dir_all(dir.join("elsewhere")).unwrap();
+        std::fs::write(dir.join("base/sub/file"), b"asdf").unwrap();
+        std::fs::write(dir.join("elsewhere/file"), b"asdf").unwrap();
+        // someone swapped base/link for a symlink to a folder outside the base path
+        std::os::unix::fs::symlink(dir.join("elsewhere"), dir.join("base/link")).unwrap();
+        std::os::unix::fs::symlink(dir.join("elsewhere/file"), dir.join("base/sub/filelink")).unwrap();
+        let dir = std::fs::canonicalize(&dir).unwrap();
+
+        let mut fs = RealFs::new(dir.join("base")).unwrap();
+        let via_symlink: PathBuf = dir.join("base/link/file");
+        assert!(fs.open(&via_symlink).is_err());
+        assert!(fs.metadata(&via_symlink).is_err());
+        assert!(fs.metadata(dir.join("base/sub/filelink")).is_err());
+        assert!(fs.remove_file(&via_symlink).is_err());
+        assert!(fs.rename(&via_symlink, dir.join("base/sub/moved")).is_err());
+        assert!(fs.hard_link(dir.join("base/sub/file"), dir.join("base/link/new")).is_err());
+        assert!(dir.join("elsewhere/file").exists());
+        assert!(!dir.join("elsewhere/new").exists());
+        // nor can ".." leave the base path
+        assert!(fs.metadata(dir.join("base/sub/../../elsewhere/file")).is_err());
+        assert!(fs.hard_link(dir.join("base/sub/file"), dir.join("base/sub/../../elsewhere/new")).is_err());
+        assert!(!dir.join("elsewhere/new").exists());
+
+        // paths outside the base path were given explicitly, like a changes log, so they may
+        // go through a symlink
+        std::os::unix::fs::symlink(dir.join("elsewhere"), dir.join("outside")).unwrap();
+        fs.append_to_file(dir.join("outside/log"), b"asdf").unwrap();
+        assert_eq!(std::fs::read(dir.join("elsewhere/log")).unwrap(), b"asdf");
+
+        // the same operations work on real paths
+        assert_eq!(fs.metadata(dir.join("base/sub/file")).unwrap().size, 4);
+        fs.hard_link(dir.join("base/sub/file"), dir.join("base/sub/new")).unwrap();
+        fs.rename(dir.join("base/sub/new"), dir.join("base/sub/moved")).unwrap();
+        fs.remove_file(dir.join("base/sub/moved")).unwrap();
+        fs.sync_dir(dir.join("base/sub")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_real_fs_changes_files_without_read_permission() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        let dir = std::env::temp_dir().join(format!("hardlink-deduplicator-permission-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file"), b"asdf").unwrap();
+        std::fs::set_permissions(dir.join("file"), std::fs::Permissions::from_mode(0o200)).unwrap();
+        std::os::unix::fs::symlink(dir.join("file"), dir.join("link")).unwrap();
+
+        let mut fs = RealFs::new(&dir).unwrap();
+        let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        fs.set_modified(dir.join("file"), modified).unwrap();
+        fs.set_mode(dir.join("file"), 0o600).unwrap();
+        let (uid, gid) = (unsafe { libc::getuid() }, unsafe { libc::getgid() });
+        fs.set_owner(dir.join("file"), uid, gid).unwrap();
+        let metadata = std::fs::metadata(dir.join("file")).unwrap();
+        assert_eq!(metadata.modified().unwrap(), modified);
+        assert_eq!(metadata.mode() & 0o7777, 0o600);
+        assert_eq!((metadata.uid(), metadata.gid()), (uid, gid));
+
+        // a symlink in the file's place is left alone
+        assert!(fs.set_mode(dir.join("link"), 0o644).is_err());
+        assert!(fs.set_xattr(dir.join("link"), "user.test", b"asdf").is_err());
+        assert_eq!(std::fs::metadata(dir.join("file")).unwrap().mode() & 0o7777, 0o600);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_real_fs_lock_file() {
+        let dir = std::env::temp_dir().join(format!("hardlink-deduplicator-lock-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut fs = RealFs::default();
+        let lock = fs.lock_file(dir.join("lock"), b"123").unwrap().unwrap();
+        assert!(fs.lock_file(dir.join("lock"), b"456").unwrap().is_none());
+        assert_eq!(std::fs::read(dir.join("lock")).unwrap(), b"123");
+        fs.unlock_file(lock).unwrap();
+        assert_eq!(std::fs::read(dir.join("lock")).unwrap(), b"");
+
+        // whatever was left in the file doesn't matter, only whether it is locked
+        std::fs::write(dir.join("lock"), "4000000000").unwrap();
+        let lock = fs.lock_file(dir.join("lock"), b"789").unwrap().unwrap();
+        assert_eq!(std::fs::read(dir.join("lock")).unwrap(), b"789");
+        drop(lock);
+        assert!(fs.lock_file(dir.join("lock"), b"123").unwrap().is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
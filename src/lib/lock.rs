@@ -0,0 +1,89 @@
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+use super::files_index::LOCK_FILE_NAME;
+use super::fs::{AbstractFs, FileLock};
+use super::{Error, ErrorKind, Result};
+
+const WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+// advisory lock on a base path: an flock() on the lock file, which holds the pid of the owner for
+// error messages. The kernel drops the lock when the owner exits, so a run that died without
+// releasing it never leaves a stale lock behind, and the next run just takes it.
+#[derive(Debug)]
+pub struct IndexLock {
+    lock: FileLock,
+}
+
+impl IndexLock {
+    // returns None if the filesystem is read-only, since a dry run can't change anything anyway
+    pub fn acquire<Fs: AbstractFs, P: AsRef<Path>>(fs: &mut Fs, base_path: P, wait: bool) -> Result<Option<Self>> {
+        let path = base_path.as_ref().join(LOCK_FILE_NAME);
+        loop {
+            match fs.lock_file(&path, std::process::id().to_string().as_bytes()) {
+                Ok(Some(lock)) => return Ok(Some(IndexLock { lock })),
+                Ok(None) => (),
+                Err(e) if e.kind() == ErrorKind::ReadOnlyFs => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            if !wait {
+                return Err(Error::locked(&path, owner(fs, &path)));
+            }
+            std::thread::sleep(WAIT_INTERVAL);
+        }
+    }
+
+    pub fn release<Fs: AbstractFs>(self, fs: &mut Fs) -> Result<()> {
+        fs.unlock_file(self.lock)
+    }
+}
+
+// None if the owner hasn't written its pid yet
+fn owner<Fs: AbstractFs>(fs: &Fs, path: &Path) -> Option<u32> {
+    let mut contents = vec![];
+    fs.open(path).ok()?.read_to_end(&mut contents).ok()?;
+    std::str::from_utf8(&contents).ok()?.trim().parse().ok()
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::lib::fs::TestFs;
+    use crate::lib::lock::IndexLock;
+    use crate::lib::ErrorKind;
+
+    #[test]
+    fn test_lock_and_release() {
+        let mut test_fs = TestFs::default();
+        let lock = IndexLock::acquire(&mut test_fs, "/somefolder/", false).unwrap().unwrap();
+        let pid = std::process::id().to_string();
+        assert_eq!(test_fs.get_file_data("/somefolder/.index_file.lock").unwrap(), pid.as_bytes());
+
+        // we already hold it, and a second lock of the same file conflicts with it
+        let e = IndexLock::acquire(&mut test_fs, "/somefolder/", false).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Locked);
+        assert!(e.to_string().contains(&format!("process {}", pid)));
+
+        // the file stays, but empty and unlocked
+        lock.release(&mut test_fs).unwrap();
+        assert_eq!(test_fs.get_file_data("/somefolder/.index_file.lock").unwrap(), b"");
+        assert!(IndexLock::acquire(&mut test_fs, "/somefolder/", false).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_lock_left_behind() {
+        // a run that died still has its pid in the file, but nobody holds the lock any more
+        let mut test_fs = TestFs::default();
+        test_fs.add_text_file("/somefolder/.index_file.lock", "4000000000");
+        assert!(IndexLock::acquire(&mut test_fs, "/somefolder/", false).unwrap().is_some());
+
+        // while the owner is still writing its pid, the lock is already held
+        let mut test_fs = TestFs::default();
+        test_fs.add_text_file("/somefolder/.index_file.lock", "");
+        test_fs.locked.insert("/somefolder/.index_file.lock".to_owned());
+        let e = IndexLock::acquire(&mut test_fs, "/somefolder/", false).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Locked);
+        assert!(e.to_string().contains("another process"));
+    }
+}
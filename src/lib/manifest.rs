@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+use super::fast_hash::{digest_file, format_digest, HashAlgorithm};
+use super::file_entry::FileEntry;
+use super::files_index::FilesIndex;
+use super::fs::AbstractFs;
+use super::path_serde;
+use super::{Error, Result};
+
+// checksum manifests in the format of sha256sum and b2sum: "<hex digest>  <path>" per line, with
+// paths relative to the base path, so `sha256sum -c` works from there
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ManifestLine {
+    pub digest: String,
+    pub relative_path: PathBuf,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Manifest {
+    pub algorithm: HashAlgorithm,
+    pub lines: Vec<ManifestLine>,
+}
+
+impl Manifest {
+    // takes bytes, since sha256sum writes the raw bytes of names that aren't UTF-8. Paths that are
+    // valid UTF-8 go through path_serde, so the manifests we write read back exactly.
+    pub fn parse(text: &[u8]) -> Result<Self> {
+        let mut algorithm = None;
+        let mut lines = vec![];
+        for (i, line) in text.split(|&b| b == b'\n').enumerate() {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() {
+                continue;
+            }
+            let bad_line = || Error::from(format!("manifest line {}: not a checksum line: {:?}", i + 1, String::from_utf8_lossy(line)));
+            // a leading backslash means the path has escaped characters in it
+            let (escaped, line) = match line.strip_prefix(b"\\") {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let mut parts = line.splitn(2, |&b| b == b' ');
+            let digest = parts.next().and_then(|d| std::str::from_utf8(d).ok()).ok_or_else(bad_line)?;
+            // the second separator character is '*' for binary mode, which means nothing on unix
+            let path = parts.next()
+                .and_then(|rest| rest.strip_prefix(b" ").or_else(|| rest.strip_prefix(b"*")))
+                .ok_or_else(bad_line)?;
+            let line_algorithm = HashAlgorithm::from_hex_len(digest.len())
+                .filter(|_| digest.chars().all(|c| c.is_ascii_hexdigit()))
+                .ok_or_else(bad_line)?;
+            if *algorithm.get_or_insert(line_algorithm) != line_algorithm {
+                return Err(format!("manifest line {}: mixes sha256 and blake2b digests", i + 1).into());
+            }
+            let path = if escaped { unescape(path) } else { path.to_vec() };
+            let relative_path = match String::from_utf8(path) {
+                Ok(path) => path_serde::decode(&path).map_err(|e| Error::from(format!("manifest line {}: {}", i + 1, e)))?,
+                Err(e) => PathBuf::from(OsString::from_vec(e.into_bytes())),
+            };
+            lines.push(ManifestLine { digest: digest.to_ascii_lowercase(), relative_path });
+        }
+        match algorithm {
+            Some(algorithm) => Ok(Manifest { algorithm, lines }),
+            None => Err("manifest is empty".into()),
+        }
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            let path = path_serde::encode(&line.relative_path);
+            if path.contains('\\') || path.contains('\n') {
+                writeln!(f, "\\{}  {}", line.digest, escape(&path))?;
+            } else {
+                writeln!(f, "{}  {}", line.digest, path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn escape(path: &str) -> String {
+    path.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(path: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(path.len());
+    let mut bytes = path.iter();
+    while let Some(&b) = bytes.next() {
+        if b != b'\\' {
+            unescaped.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'n') => unescaped.push(b'\n'),
+            Some(&b) => unescaped.push(b),
+            None => unescaped.push(b'\\'),
+        }
+    }
+    unescaped
+}
+
+#[derive(Debug)]
+pub struct Export {
+    pub manifest: Manifest,
+    // left out of the manifest
+    pub unreadable: Vec<(PathBuf, String)>,
+}
+
+// every indexed path under the base path, with the paths of a hard linked group next to each other.
+// Each inode is only read once, and the digests are stored in the index so the next export doesn't
+// need to read it. A stored digest is only used while the file still has the mtime and ctime of its
+// index entry; once it changed, the file is read again.
+pub fn export<Fs: AbstractFs>(fs: &Fs, index: &mut FilesIndex, algorithm: HashAlgorithm) -> Export {
+    let mut groups: HashMap<(u64, u64), Vec<FileEntry>> = HashMap::new();
+    for entry in index.entries().iter().filter(|e| !e.is_reference()) {
+        groups.entry(entry.file_id()).or_default().push(entry.clone());
+    }
+    let mut groups: Vec<Vec<FileEntry>> = groups.into_iter()
+        .map(|(_, mut group)| {
+            group.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+            group
+        })
+        .collect();
+    groups.sort_by(|a, b| a[0].relative_path.cmp(&b[0].relative_path));
+
+    let mut lines = vec![];
+    let mut unreadable = vec![];
+    for group in groups {
+        let digest = group[0].agrees_with_disk(fs, &index.base_path).and_then(|unchanged| {
+            match group.iter().find_map(|e| e.digest_for(algorithm)) {
+                Some(digest) if unchanged => Ok((digest.to_owned(), true)),
+                _ => digest_file(fs, &group[0].absolute_path(&index.base_path), algorithm).map(|d| (d, unchanged)),
+            }
+        });
+        let (digest, unchanged) = match digest {
+            Ok(digest) => digest,
+            Err(e) => {
+                unreadable.extend(group.into_iter().map(|entry| (entry.relative_path, e.to_string())));
+                continue;
+            }
+        };
+        for entry in group {
+            // the entry describes the file as it was before, so the new digest doesn't belong to it
+            if unchanged {
+                index.set_digest(&entry.relative_path, format_digest(algorithm, &digest));
+            }
+            lines.push(ManifestLine { digest: digest.clone(), relative_path: entry.relative_path });
+        }
+    }
+    Export { manifest: Manifest { algorithm, lines }, unreadable }
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub seeded: usize,
+    // the index already has a different digest for these, made with the same algorithm
+    pub conflicting: Vec<PathBuf>,
+    // not in the index, or they changed since it was written
+    pub unknown: usize,
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "seeded {} digests", self.seeded)?;
+        for path in &self.conflicting {
+            writeln!(f, "conflict: {:?} has a different digest in the index", path)?;
+        }
+        if self.unknown > 0 {
+            writeln!(f, "{} paths in the manifest are not in the index, or changed since it was written", self.unknown)?;
+        }
+        Ok(())
+    }
+}
+
+// seeds the digests of indexed files from the manifest. Entries that already have a digest made
+// with another algorithm keep it. Each file is checked against its index entry first, so the
+// digest goes with the mtime and ctime the file has now, and export reads it again once they
+// change.
+pub fn import<Fs: AbstractFs>(fs: &Fs, index: &mut FilesIndex, manifest: &Manifest) -> ImportReport {
+    let mut report = ImportReport::default();
+    for line in &manifest.lines {
+        let entry = match index.get_by_relative_path(&line.relative_path) {
+            Some(entry) if entry.agrees_with_disk(fs, &index.base_path).unwrap_or(false) => entry,
+            _ => {
+                report.unknown += 1;
+                continue;
+            }
+        };
+        match (entry.digest_for(manifest.algorithm), &entry.digest) {
+            (Some(digest), _) if digest == line.digest => (),
+            (Some(_), _) => report.conflicting.push(line.relative_path.clone()),
+            (None, Some(_)) => (),
+            (None, None) => {
+                index.set_digest(&line.relative_path, format_digest(manifest.algorithm, &line.digest));
+                report.seeded += 1;
+            }
+        }
+    }
+    report
+}
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub ok: usize,
+    pub mismatched: Vec<PathBuf>,
+    pub unreadable: Vec<(PathBuf, String)>,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.mismatched.is_empty() && self.unreadable.is_empty()
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for path in &self.mismatched {
+            writeln!(f, "{}: FAILED", path.display())?;
+        }
+        for (path, e) in &self.unreadable {
+            writeln!(f, "{}: FAILED open or read: {}", path.display(), e)?;
+        }
+        writeln!(f, "{} files OK, {} failed", self.ok, self.mismatched.len() + self.unreadable.len())
+    }
+}
+
+// like `sha256sum -c`, reads every file in the manifest, but only once per inode
+pub fn check<Fs: AbstractFs, P: AsRef<Path>>(fs: &Fs, base_path: P, manifest: &Manifest) -> CheckReport {
+    let mut report = CheckReport::default();
+    let mut digests: HashMap<(u64, u64), String> = HashMap::new();
+    for line in &manifest.lines {
+        let abs_path = base_path.as_ref().join(&line.relative_path);
+        let digest = fs.metadata(&abs_path).and_then(|metadata| {
+            let file_id = (metadata.device, metadata.inode);
+            if let Some(digest) = digests.get(&file_id) {
+                return Ok(digest.clone());
+            }
+            let digest = digest_file(fs, &abs_path, manifest.algorithm)?;
+            digests.insert(file_id, digest.clone());
+            Ok(digest)
+        });
+        match digest {
+            Ok(digest) if digest == line.digest => report.ok += 1,
+            Ok(_) => report.mismatched.push(line.relative_path.clone()),
+            Err(e) => report.unreadable.push((line.relative_path.clone(), e.to_string())),
+        }
+    }
+    report
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    use crate::lib::fast_hash::HashAlgorithm;
+    use crate::lib::files_index::FilesIndex;
+    use crate::lib::fs::TestFs;
+    use crate::lib::manifest::{check, export, import, Manifest};
+
+    const ASDF: &str = "f0e4c2f76c58916ec258f246851bea091d14d4247a2fc3e18694461b1816e13b";
+    const QWER: &str = "f6f2ea8f45d8a057c9566a33f99474da2e5c6a6604d736121650e2730c6fb0a3";
+
+    fn test_index(test_fs: &mut TestFs) -> FilesIndex {
+        test_fs.set_cwd("/somefolder/");
+        let mut index = FilesIndex::from_checked_entries("/somefolder/", vec![]);
+        for (name, content) in [("b", "asdf"), ("a", "qwer"), ("c", "asdf")].iter() {
+            let f = test_fs.new_file_entry(&format!("/somefolder/{}", name), content);
+            index.add_file(test_fs, f.relative_path.as_path()).unwrap();
+        }
+        index
+    }
+
+    #[test]
+    fn test_export() {
+        let mut test_fs = TestFs::default();
+        let mut index = test_index(&mut test_fs);
+        let manifest = export(&test_fs, &mut index, HashAlgorithm::Sha256).manifest;
+        // b and c are hard links, so they come out together
+        assert_eq!(manifest.to_string(), format!("{}  a\n{}  b\n{}  c\n", QWER, ASDF, ASDF));
+        assert_eq!(index.get_by_relative_path(&"c").unwrap().digest_for(HashAlgorithm::Sha256), Some(ASDF));
+
+        let parsed = Manifest::parse(manifest.to_string().as_bytes()).unwrap();
+        assert_eq!(parsed, manifest);
+    }
+
+    #[test]
+    fn test_export_reads_changed_files_again() {
+        let mut test_fs = TestFs::default();
+        let mut index = test_index(&mut test_fs);
+        export(&test_fs, &mut index, HashAlgorithm::Sha256);
+
+        // a changed file doesn't get its stored digest, and an unreadable one doesn't stop the rest
+        test_fs.write_in_place("/somefolder/a", b"asdf");
+        test_fs.unreadable.insert("/somefolder/b".to_owned());
+        let export = export(&test_fs, &mut index, HashAlgorithm::Sha256);
+        assert_eq!(export.manifest.to_string(), format!("{}  a\n", ASDF));
+        let unreadable: Vec<PathBuf> = export.unreadable.into_iter().map(|(path, _)| path).collect();
+        assert_eq!(unreadable, vec![PathBuf::from("b"), PathBuf::from("c")]);
+        // the index entry of a is out of date, so it keeps the digest that goes with it
+        assert_eq!(index.get_by_relative_path(&"a").unwrap().digest_for(HashAlgorithm::Sha256), Some(QWER));
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = format!("{}  a\n{} *b\n\\{}  c\\\\d\\ne\n", QWER, ASDF.to_uppercase(), ASDF);
+        let manifest = Manifest::parse(text.as_bytes()).unwrap();
+        assert_eq!(manifest.algorithm, HashAlgorithm::Sha256);
+        assert_eq!(manifest.lines[1].digest, ASDF);
+        assert_eq!(manifest.lines[2].relative_path, PathBuf::from("c\\d\ne"));
+        assert_eq!(manifest.to_string().lines().nth(2).unwrap(), format!("\\{}  c\\\\d\\ne", ASDF));
+
+        assert!(Manifest::parse(b"").is_err());
+        assert!(Manifest::parse(b"abcd  a\n").is_err());
+        assert!(Manifest::parse(format!("{}a\n", ASDF).as_bytes()).is_err());
+
+        // names that aren't UTF-8 are written encoded, and read back either way
+        let raw = [format!("{}  caf", ASDF).as_bytes(), b"\xe9\n"].concat();
+        let manifest = Manifest::parse(&raw).unwrap();
+        assert_eq!(manifest.lines[0].relative_path, PathBuf::from(OsStr::from_bytes(b"caf\xe9")));
+        assert_eq!(manifest.to_string(), format!("{}  base64:Y2Fm6Q==\n", ASDF));
+        assert_eq!(Manifest::parse(manifest.to_string().as_bytes()).unwrap(), manifest);
+    }
+
+    #[test]
+    fn test_import_and_check() {
+        let mut test_fs = TestFs::default();
+        let mut index = test_index(&mut test_fs);
+        let wrong = "0".repeat(64);
+        let manifest = Manifest::parse(format!("{}  a\n{}  b\n{}  missing\n", QWER, wrong, ASDF).as_bytes()).unwrap();
+
+        let report = import(&test_fs, &mut index, &manifest);
+        assert_eq!((report.seeded, report.unknown), (2, 1));
+        assert_eq!(index.get_by_relative_path(&"a").unwrap().digest_for(HashAlgorithm::Sha256), Some(QWER));
+        // importing it again doesn't change anything, a different digest is a conflict
+        let manifest = Manifest::parse(format!("{}  a\n{}  b\n", QWER, ASDF).as_bytes()).unwrap();
+        let report = import(&test_fs, &mut index, &manifest);
+        assert_eq!(report.seeded, 0);
+        assert_eq!(report.conflicting, vec![PathBuf::from("b")]);
+
+        // a file that changed after the index was written doesn't take a digest
+        test_fs.write_in_place("/somefolder/c", b"zxcv");
+        let report = import(&test_fs, &mut index, &Manifest::parse(format!("{}  c\n", ASDF).as_bytes()).unwrap());
+        assert_eq!((report.seeded, report.unknown), (0, 1));
+        assert_eq!(index.get_by_relative_path(&"c").unwrap().digest, None);
+        test_fs.write_in_place("/somefolder/c", b"asdf");
+
+        let manifest = Manifest::parse(format!("{}  a\n{}  b\n{}  c\n{}  missing\n", QWER, wrong, ASDF, ASDF).as_bytes()).unwrap();
+        let report = check(&test_fs, "/somefolder/", &manifest);
+        assert_eq!(report.ok, 2);
+        assert_eq!(report.mismatched, vec![PathBuf::from("b")]);
+        assert_eq!(report.unreadable.len(), 1);
+    }
+}
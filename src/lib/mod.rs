@@ -2,6 +2,16 @@ pub mod files_index;
 pub mod fs;
 pub mod fast_hash;
 pub mod file_entry;
+pub mod check;
+pub mod lock;
+pub mod audit;
+pub mod verify;
+pub mod manifest;
+pub mod reconcile;
+pub mod path_serde;
+pub mod binary_index;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_index;
 
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -9,6 +19,8 @@ pub type Result<T> = std::result::Result<T, Error>;
 // use std::backtrace::Backtrace;
 extern crate backtrace;
 use backtrace::Backtrace;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
 
 #[derive(Debug)]
@@ -17,7 +29,204 @@ pub enum Error {
     IO(Backtrace, std::io::Error),
     StripPrefixError(Backtrace, std::path::StripPrefixError),
     ReadOnlyFs(),
+    // the path matches one of the protected patterns, see fs::GuardedFs
+    Protected(),
     Csv(Backtrace, csv::Error),
+    // a file was modified by something else while we were looking at it
+    ChangedDuringScan(Backtrace, String),
+    // a file was modified too recently, it may still be being written to
+    Unsettled(Backtrace, String),
+    // something that the index relies on turned out not to be true
+    InvariantViolation(Backtrace, String),
+    // another run holds the lock on this base path
+    Locked(Backtrace, String),
+    // a file no longer matches its stored hash even though its metadata didn't change
+    Corrupt(Backtrace, String),
+    // the index file is in a format this version doesn't know, see files_index::IndexHeader
+    UnsupportedIndex(Backtrace, String),
+    // wraps another error with the operation and path(s) it happened on
+    Context {
+        op: &'static str,
+        paths: Vec<PathBuf>,
+        source: Box<Error>,
+    },
+}
+
+// broad classification of errors, mostly so we can decide on an exit code
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ErrorKind {
+    PermissionDenied,
+    NotFound,
+    CrossDevice,
+    ChangedDuringScan,
+    Unsettled,
+    InvariantViolation,
+    Locked,
+    Corrupt,
+    UnsupportedIndex,
+    ReadOnlyFs,
+    Protected,
+    Other,
+}
+
+impl ErrorKind {
+    // documented in the `--help` output in main.rs
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::ReadOnlyFs => 1,
+            ErrorKind::Protected => 1,
+            ErrorKind::NotFound => 3,
+            ErrorKind::PermissionDenied => 4,
+            ErrorKind::CrossDevice => 5,
+            ErrorKind::ChangedDuringScan => 6,
+            ErrorKind::Unsettled => 6,
+            ErrorKind::InvariantViolation => 7,
+            ErrorKind::Locked => 8,
+            ErrorKind::Corrupt => 9,
+            ErrorKind::UnsupportedIndex => 10,
+        }
+    }
+}
+
+impl Error {
+    pub fn changed_during_scan<P: AsRef<Path>>(path: P, what: &str) -> Self {
+        Error::ChangedDuringScan(
+            Backtrace::new(),
+            format!("{} changed during scan: {}", path.as_ref().display(), what),
+        )
+    }
+
+    pub fn unsettled<P: AsRef<Path>>(path: P) -> Self {
+        Error::Unsettled(
+            Backtrace::new(),
+            format!("{} was modified too recently, skipping it for now", path.as_ref().display()),
+        )
+    }
+
+    pub fn invariant<S: Into<String>>(message: S) -> Self {
+        Error::InvariantViolation(Backtrace::new(), message.into())
+    }
+
+    pub fn locked<P: AsRef<Path>>(lock_path: P, pid: Option<u32>) -> Self {
+        let owner = match pid {
+            Some(pid) => format!("process {}", pid),
+            None => "another process".to_owned(),
+        };
+        Error::Locked(Backtrace::new(), format!("{} is held by {}", lock_path.as_ref().display(), owner))
+    }
+
+    pub fn corrupt<S: Into<String>>(message: S) -> Self {
+        Error::Corrupt(Backtrace::new(), message.into())
+    }
+
+    pub fn unsupported_index<S: Into<String>>(message: S) -> Self {
+        Error::UnsupportedIndex(Backtrace::new(), message.into())
+    }
+
+    pub fn with_path<P: AsRef<Path>>(self, op: &'static str, path: P) -> Self {
+        Error::Context { op, paths: vec![path.as_ref().to_owned()], source: Box::new(self) }
+    }
+
+    pub fn with_paths<P: AsRef<Path>, Q: AsRef<Path>>(self, op: &'static str, path1: P, path2: Q) -> Self {
+        Error::Context {
+            op,
+            paths: vec![path1.as_ref().to_owned(), path2.as_ref().to_owned()],
+            source: Box::new(self),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::IO(_, e) if e.raw_os_error() == Some(libc::EXDEV) => ErrorKind::CrossDevice,
+            Error::IO(_, e) => match e.kind() {
+                std::io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+                std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+                _ => ErrorKind::Other,
+            },
+            Error::ReadOnlyFs() => ErrorKind::ReadOnlyFs,
+            Error::Protected() => ErrorKind::Protected,
+            Error::ChangedDuringScan(_, _) => ErrorKind::ChangedDuringScan,
+            Error::Unsettled(_, _) => ErrorKind::Unsettled,
+            Error::InvariantViolation(_, _) => ErrorKind::InvariantViolation,
+            Error::Locked(_, _) => ErrorKind::Locked,
+            Error::Corrupt(_, _) => ErrorKind::Corrupt,
+            Error::UnsupportedIndex(_, _) => ErrorKind::UnsupportedIndex,
+            Error::Context { source, .. } => source.kind(),
+            Error::Generic(_, _) | Error::StripPrefixError(_, _) | Error::Csv(_, _) => ErrorKind::Other,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.kind().exit_code()
+    }
+
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        match self {
+            Error::Generic(b, _) | Error::IO(b, _) | Error::StripPrefixError(b, _) | Error::Csv(b, _)
+            | Error::ChangedDuringScan(b, _) | Error::Unsettled(b, _) | Error::InvariantViolation(b, _) | Error::Locked(b, _)
+            | Error::Corrupt(b, _) | Error::UnsupportedIndex(b, _) => Some(b),
+            Error::ReadOnlyFs() | Error::Protected() => None,
+            Error::Context { source, .. } => source.backtrace(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Generic(_, s) => write!(f, "{}", s),
+            Error::IO(_, e) => write!(f, "{}", e),
+            Error::StripPrefixError(_, e) => write!(f, "{}", e),
+            Error::ReadOnlyFs() => write!(f, "filesystem is read-only (dry run)"),
+            Error::Protected() => write!(f, "path is protected"),
+            Error::Csv(_, e) => write!(f, "bad index file: {}", e),
+            Error::ChangedDuringScan(_, s) => write!(f, "{}", s),
+            Error::Unsettled(_, s) => write!(f, "{}", s),
+            Error::InvariantViolation(_, s) => write!(f, "invariant violated: {}", s),
+            Error::Locked(_, s) => write!(f, "another run is in progress: {}", s),
+            Error::Corrupt(_, s) => write!(f, "{}", s),
+            Error::UnsupportedIndex(_, s) => write!(f, "{}", s),
+            Error::Context { op, paths, source } => {
+                write!(f, "{} ", op)?;
+                for (i, path) in paths.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " -> ")?;
+                    }
+                    write!(f, "{:?}", path)?;
+                }
+                write!(f, ": {}", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IO(_, e) => Some(e),
+            Error::StripPrefixError(_, e) => Some(e),
+            Error::Csv(_, e) => Some(e),
+            Error::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+// lets us write `fs_op(path).context("open", path)?`
+pub trait ResultExt<T> {
+    fn context<P: AsRef<Path>>(self, op: &'static str, path: P) -> Result<T>;
+    fn context2<P: AsRef<Path>, Q: AsRef<Path>>(self, op: &'static str, path1: P, path2: Q) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for std::result::Result<T, E> {
+    fn context<P: AsRef<Path>>(self, op: &'static str, path: P) -> Result<T> {
+        self.map_err(|e| e.into().with_path(op, path))
+    }
+
+    fn context2<P: AsRef<Path>, Q: AsRef<Path>>(self, op: &'static str, path1: P, path2: Q) -> Result<T> {
+        self.map_err(|e| e.into().with_paths(op, path1, path2))
+    }
 }
 
 impl From<String> for Error {
@@ -50,3 +259,33 @@ impl From<csv::Error> for Error {
         Error::Csv(Backtrace::new(), e)
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::{Error, ErrorKind, ResultExt};
+    use std::io;
+
+    #[test]
+    fn test_kind() {
+        let e: Error = io::Error::from(io::ErrorKind::NotFound).into();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        let e: Error = io::Error::from(io::ErrorKind::PermissionDenied).into();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        let e: Error = io::Error::from_raw_os_error(libc::EXDEV).into();
+        assert_eq!(e.kind(), ErrorKind::CrossDevice);
+        assert_eq!(Error::from("asdf").kind(), ErrorKind::Other);
+        assert_eq!(Error::invariant("asdf").exit_code(), 7);
+    }
+
+    #[test]
+    fn test_context() {
+        let r: std::result::Result<(), io::Error> = Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        let e = r.context2("hard link", "/a/b", "/a/c").unwrap_err();
+        // the kind comes from the wrapped error
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(e.exit_code(), 4);
+        let s = e.to_string();
+        assert!(s.starts_with("hard link \"/a/b\" -> \"/a/c\": "), "{}", s);
+    }
+}
@@ -0,0 +1,59 @@
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serializer};
+
+// paths are stored as they are when they are valid UTF-8, and as the marker followed by the
+// base64 of their raw bytes otherwise. Names that happen to start with the marker are encoded as
+// well, so every path reads back exactly as it was written. Use with #[serde(with = "path_serde")].
+const MARKER: &str = "base64:";
+
+pub fn encode(path: &Path) -> Cow<'_, str> {
+    match path.to_str() {
+        Some(s) if !s.starts_with(MARKER) => Cow::Borrowed(s),
+        _ => Cow::Owned(format!("{}{}", MARKER, base64::encode(path.as_os_str().as_bytes()))),
+    }
+}
+
+pub fn decode(s: &str) -> std::result::Result<PathBuf, String> {
+    match s.strip_prefix(MARKER) {
+        Some(encoded) => base64::decode(encoded)
+            .map(|bytes| PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+            .map_err(|e| format!("bad path {:?}: {}", s, e)),
+        None => Ok(PathBuf::from(OsStr::new(s))),
+    }
+}
+
+pub fn serialize<P: AsRef<Path>, S: Serializer>(path: &P, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode(path.as_ref()))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<PathBuf, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    decode(&s).map_err(D::Error::custom)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+
+    use crate::lib::path_serde::{decode, encode};
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(Path::new("a/b c,\"d\"\n")), "a/b c,\"d\"\n");
+        let invalid = Path::new(OsStr::from_bytes(b"caf\xe9"));
+        assert_eq!(encode(invalid), "base64:Y2Fm6Q==");
+        assert_eq!(encode(Path::new("base64:abc")), "base64:YmFzZTY0OmFiYw==");
+        for path in [invalid, Path::new("base64:abc"), Path::new("plain")].iter() {
+            assert_eq!(decode(&encode(path)).unwrap(), PathBuf::from(path));
+        }
+        assert!(decode("base64:not base64").is_err());
+    }
+}
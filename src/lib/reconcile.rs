@@ -0,0 +1,243 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use super::fs::Metadata;
+
+// after a path is linked to another file, it takes on that file's mtime, mode and owner along
+// with the contents. These rules decide what the inode they share ends up with instead.
+
+// bits that never carry over from the replaced file: the tool runs as root over trees that users
+// can write to, and a user could otherwise chmod their copy of a root owned file to get a setuid
+// or world writable root owned file
+const NEVER_MERGED_MODE_BITS: u32 = 0o7022;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MtimeRule {
+    // whatever the file that was linked to has
+    Keep,
+    Oldest,
+    Newest,
+}
+
+impl Default for MtimeRule {
+    fn default() -> Self {
+        MtimeRule::Keep
+    }
+}
+
+impl FromStr for MtimeRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(MtimeRule::Keep),
+            "oldest" => Ok(MtimeRule::Oldest),
+            "newest" => Ok(MtimeRule::Newest),
+            _ => Err(format!("unknown mtime rule {:?}, expected keep, oldest or newest", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ModeRule {
+    Keep,
+    // only the permission bits both files have
+    MostRestrictive,
+    // every permission bit either file has, except for NEVER_MERGED_MODE_BITS of the replaced file
+    LeastRestrictive,
+}
+
+impl Default for ModeRule {
+    fn default() -> Self {
+        ModeRule::Keep
+    }
+}
+
+impl FromStr for ModeRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(ModeRule::Keep),
+            "most-restrictive" => Ok(ModeRule::MostRestrictive),
+            "least-restrictive" => Ok(ModeRule::LeastRestrictive),
+            _ => Err(format!("unknown mode rule {:?}, expected keep, most-restrictive or least-restrictive", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OwnerRule {
+    Keep,
+    // files with different owners end up owned by root, so no user owns the contents another
+    // user's path now shows. Taking the replaced file's owner instead would hand a root owned
+    // inode to whoever made the copy.
+    RootIfDifferent,
+}
+
+impl Default for OwnerRule {
+    fn default() -> Self {
+        OwnerRule::Keep
+    }
+}
+
+impl FromStr for OwnerRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(OwnerRule::Keep),
+            "root-if-different" => Ok(OwnerRule::RootIfDifferent),
+            _ => Err(format!("unknown owner rule {:?}, expected keep or root-if-different", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Policy {
+    pub mtime: MtimeRule,
+    pub mode: ModeRule,
+    pub owner: OwnerRule,
+}
+
+impl Policy {
+    pub fn keeps_everything(&self) -> bool {
+        *self == Policy::default()
+    }
+
+    // what has to change on the linked-to file, given what the file it replaced looked like.
+    // Applied after every link, so over a whole group the oldest mtime wins, and so on.
+    pub fn changes(&self, linked_to: &Metadata, replaced: &Metadata) -> Vec<MetadataChange> {
+        let mut changes = vec![];
+        let modified = match self.mtime {
+            MtimeRule::Keep => linked_to.modified,
+            MtimeRule::Oldest => linked_to.modified.min(replaced.modified),
+            MtimeRule::Newest => linked_to.modified.max(replaced.modified),
+        };
+        if modified != linked_to.modified {
+            changes.push(MetadataChange::Mtime(linked_to.modified, modified));
+        }
+        let mut mode = match self.mode {
+            ModeRule::Keep => linked_to.mode,
+            ModeRule::MostRestrictive => linked_to.mode & replaced.mode,
+            ModeRule::LeastRestrictive => linked_to.mode | (replaced.mode & !NEVER_MERGED_MODE_BITS),
+        };
+        let owner = match self.owner {
+            OwnerRule::Keep => (linked_to.uid, linked_to.gid),
+            OwnerRule::RootIfDifferent if (linked_to.uid, linked_to.gid) != (replaced.uid, replaced.gid) => (0, 0),
+            OwnerRule::RootIfDifferent => (linked_to.uid, linked_to.gid),
+        };
+        // chown can clear setuid and setgid, so it goes before the chmod
+        if owner != (linked_to.uid, linked_to.gid) {
+            changes.push(MetadataChange::Owner((linked_to.uid, linked_to.gid), owner));
+            // the bits the previous owner chose don't carry over to root either
+            mode &= !NEVER_MERGED_MODE_BITS;
+        }
+        if mode != linked_to.mode {
+            changes.push(MetadataChange::Mode(linked_to.mode, mode));
+        }
+        changes
+    }
+}
+
+// (before, after)
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MetadataChange {
+    Mtime(SystemTime, SystemTime),
+    Mode(u32, u32),
+    // (uid, gid)
+    Owner((u32, u32), (u32, u32)),
+}
+
+impl MetadataChange {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MetadataChange::Mtime(..) => "mtime",
+            MetadataChange::Mode(..) => "mode",
+            MetadataChange::Owner(..) => "owner",
+        }
+    }
+}
+
+impl fmt::Display for MetadataChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataChange::Mtime(before, after) => write!(f, "{} -> {}", unix_time(*before), unix_time(*after)),
+            MetadataChange::Mode(before, after) => write!(f, "{:04o} -> {:04o}", before, after),
+            MetadataChange::Owner(before, after) => write!(f, "{}:{} -> {}:{}", before.0, before.1, after.0, after.1),
+        }
+    }
+}
+
+// like `date +@%s.%N` takes it
+fn unix_time(time: SystemTime) -> String {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) if d.subsec_nanos() == 0 => format!("@{}", d.as_secs()),
+        Ok(d) => format!("@{}.{:09}", d.as_secs(), d.subsec_nanos()),
+        Err(_) => "before 1970".to_owned(),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use crate::lib::fs::Metadata;
+    use crate::lib::reconcile::{MetadataChange, ModeRule, MtimeRule, OwnerRule, Policy};
+
+    fn metadata(modified_secs: u64, mode: u32) -> Metadata {
+        Metadata {
+            size: 4,
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(modified_secs),
+            accessed: SystemTime::UNIX_EPOCH,
+            created: SystemTime::UNIX_EPOCH,
+            changed: SystemTime::UNIX_EPOCH,
+            inode: 2,
+            device: 1,
+            mode,
+            uid: 0,
+            gid: 0,
+        }
+    }
+
+    #[test]
+    fn test_changes() {
+        let linked_to = metadata(20, 0o644);
+        let replaced = metadata(10, 0o600);
+        assert!(Policy::default().changes(&linked_to, &replaced).is_empty());
+
+        let policy = Policy { mtime: MtimeRule::Oldest, mode: ModeRule::MostRestrictive, owner: OwnerRule::Keep };
+        let changes = policy.changes(&linked_to, &replaced);
+        assert_eq!(changes, vec![
+            MetadataChange::Mtime(linked_to.modified, replaced.modified),
+            MetadataChange::Mode(0o644, 0o600),
+        ]);
+        assert_eq!(changes[0].to_string(), "@20 -> @10");
+        assert_eq!(changes[1].to_string(), "0644 -> 0600");
+
+        let policy = Policy { mtime: MtimeRule::Newest, mode: ModeRule::LeastRestrictive, owner: OwnerRule::Keep };
+        assert_eq!(policy.changes(&linked_to, &metadata(10, 0o754)), vec![MetadataChange::Mode(0o644, 0o754)]);
+        // group and other write, setuid, setgid and sticky never spread from the replaced file
+        assert!(policy.changes(&linked_to, &metadata(10, 0o660)).is_empty());
+        assert!(policy.changes(&metadata(20, 0o755), &metadata(10, 0o4777)).is_empty());
+        assert_eq!(policy.changes(&metadata(20, 0o4700), &metadata(10, 0o7777)), vec![MetadataChange::Mode(0o4700, 0o4755)]);
+
+        let policy = Policy { owner: OwnerRule::RootIfDifferent, ..Policy::default() };
+        let users_copy = Metadata { uid: 1000, gid: 100, ..metadata(10, 0o644) };
+        assert!(policy.changes(&linked_to, &metadata(10, 0o644)).is_empty());
+        assert_eq!(policy.changes(&users_copy, &linked_to), vec![MetadataChange::Owner((1000, 100), (0, 0))]);
+        assert_eq!(policy.changes(&users_copy, &linked_to)[0].to_string(), "1000:100 -> 0:0");
+        let users_setuid_copy = Metadata { mode: 0o4777, ..users_copy };
+        assert_eq!(policy.changes(&users_setuid_copy, &linked_to), vec![
+            MetadataChange::Owner((1000, 100), (0, 0)),
+            MetadataChange::Mode(0o4777, 0o755),
+        ]);
+        // root already owns it
+        assert!(policy.changes(&linked_to, &users_copy).is_empty());
+        assert_eq!("root-if-different".parse::<OwnerRule>(), Ok(OwnerRule::RootIfDifferent));
+        assert_eq!("most-restrictive".parse::<ModeRule>(), Ok(ModeRule::MostRestrictive));
+        assert!("oldest-first".parse::<MtimeRule>().is_err());
+    }
+}
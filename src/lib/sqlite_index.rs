@@ -0,0 +1,343 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use rusqlite::types::{Type, Value, ValueRef};
+use rusqlite::{params, Connection, OpenFlags, Row};
+
+use super::file_entry::FileEntry;
+use super::files_index::IndexHeader;
+use super::{Error, Result};
+
+// the index as a sqlite database, for looking at it with ad hoc queries. Only the rows that
+// changed are written on save, instead of the whole file.
+//
+// entries has one row per path, like the csv. inodes and hash_groups are kept up to date from it
+// by triggers, with how many paths each inode has, and how many inodes and paths have the same
+// hash and size. For example the groups that waste the most space:
+//
+//   SELECT fast_hash, size, inodes, (inodes - 1) * size AS wasted FROM hash_groups ORDER BY wasted DESC
+//
+// Paths are text, or blobs when they aren't valid UTF-8. Times are nanoseconds since 1970, and
+// hashes are decimal text, like in the csv.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS header (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    format TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    tool_version TEXT NOT NULL,
+    fast_hash TEXT NOT NULL,
+    base_path NOT NULL,
+    created INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS entries (
+    path PRIMARY KEY,
+    directory NOT NULL,
+    fast_hash TEXT,
+    size INTEGER NOT NULL,
+    modified INTEGER NOT NULL,
+    created INTEGER NOT NULL,
+    inode INTEGER NOT NULL,
+    device INTEGER NOT NULL,
+    changed INTEGER NOT NULL,
+    keep_separate INTEGER NOT NULL,
+    digest TEXT
+);
+CREATE INDEX IF NOT EXISTS entries_by_inode ON entries (device, inode);
+CREATE INDEX IF NOT EXISTS entries_by_directory ON entries (directory);
+
+CREATE TABLE IF NOT EXISTS inodes (
+    device INTEGER NOT NULL,
+    inode INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    fast_hash TEXT,
+    paths INTEGER NOT NULL,
+    PRIMARY KEY (device, inode)
+);
+CREATE INDEX IF NOT EXISTS inodes_by_hash ON inodes (fast_hash, size);
+
+CREATE TABLE IF NOT EXISTS hash_groups (
+    fast_hash TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    inodes INTEGER NOT NULL,
+    paths INTEGER NOT NULL,
+    PRIMARY KEY (fast_hash, size)
+);
+
+CREATE TRIGGER IF NOT EXISTS entries_insert AFTER INSERT ON entries BEGIN
+    INSERT INTO inodes (device, inode, size, fast_hash, paths)
+        VALUES (new.device, new.inode, new.size, new.fast_hash, 1)
+        ON CONFLICT (device, inode) DO UPDATE
+            SET size = excluded.size, fast_hash = excluded.fast_hash, paths = paths + 1;
+END;
+CREATE TRIGGER IF NOT EXISTS entries_delete AFTER DELETE ON entries BEGIN
+    UPDATE inodes SET paths = paths - 1 WHERE device = old.device AND inode = old.inode;
+    DELETE FROM inodes WHERE device = old.device AND inode = old.inode AND paths = 0;
+END;
+CREATE TRIGGER IF NOT EXISTS entries_update AFTER UPDATE ON entries BEGIN
+    UPDATE inodes SET paths = paths - 1 WHERE device = old.device AND inode = old.inode;
+    DELETE FROM inodes WHERE device = old.device AND inode = old.inode AND paths = 0;
+    INSERT INTO inodes (device, inode, size, fast_hash, paths)
+        VALUES (new.device, new.inode, new.size, new.fast_hash, 1)
+        ON CONFLICT (device, inode) DO UPDATE
+            SET size = excluded.size, fast_hash = excluded.fast_hash, paths = paths + 1;
+END;
+
+CREATE TRIGGER IF NOT EXISTS inodes_insert AFTER INSERT ON inodes WHEN new.fast_hash IS NOT NULL BEGIN
+    INSERT INTO hash_groups (fast_hash, size, inodes, paths)
+        VALUES (new.fast_hash, new.size, 1, new.paths)
+        ON CONFLICT (fast_hash, size) DO UPDATE
+            SET inodes = inodes + 1, paths = paths + excluded.paths;
+END;
+CREATE TRIGGER IF NOT EXISTS inodes_delete AFTER DELETE ON inodes WHEN old.fast_hash IS NOT NULL BEGIN
+    UPDATE hash_groups SET inodes = inodes - 1, paths = paths - old.paths
+        WHERE fast_hash = old.fast_hash AND size = old.size;
+    DELETE FROM hash_groups WHERE fast_hash = old.fast_hash AND size = old.size AND inodes = 0;
+END;
+CREATE TRIGGER IF NOT EXISTS inodes_update AFTER UPDATE ON inodes BEGIN
+    UPDATE hash_groups SET inodes = inodes - 1, paths = paths - old.paths
+        WHERE fast_hash = old.fast_hash AND size = old.size;
+    DELETE FROM hash_groups WHERE fast_hash = old.fast_hash AND size = old.size AND inodes = 0;
+    INSERT INTO hash_groups (fast_hash, size, inodes, paths)
+        SELECT new.fast_hash, new.size, 1, new.paths WHERE new.fast_hash IS NOT NULL
+        ON CONFLICT (fast_hash, size) DO UPDATE
+            SET inodes = inodes + 1, paths = paths + excluded.paths;
+END;
+";
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::from(format!("sqlite: {}", e))
+    }
+}
+
+// sqlite opens the database and its journal itself, never through a symlink. The caller checks
+// them through fs first, see FilesIndex::check_sqlite_files.
+pub fn load(db_path: &Path) -> Result<(IndexHeader, Vec<FileEntry>)> {
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NOFOLLOW)?;
+    let header = conn.query_row(
+        "SELECT format, version, tool_version, fast_hash, base_path, created FROM header",
+        params![],
+        |row| Ok(IndexHeader {
+            format: row.get(0)?,
+            version: row.get(1)?,
+            tool_version: row.get(2)?,
+            fast_hash: row.get(3)?,
+            base_path: path_from_sql(row.get_raw(4)),
+            created: time_from_sql(row.get(5)?),
+        }),
+    )?;
+
+    let mut stmt = conn.prepare(
+        "SELECT path, fast_hash, size, modified, created, inode, device, changed, keep_separate, digest FROM entries")?;
+    let entries = stmt.query_map(params![], entry_from_row)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok((header, entries))
+}
+
+// brings the database in line with the entries, in one transaction
+pub fn save<'a, I: IntoIterator<Item = &'a FileEntry>>(db_path: &Path, header: &IndexHeader, entries: I) -> Result<()> {
+    let mut conn = Connection::open_with_flags(db_path, OpenFlags::default() | OpenFlags::SQLITE_OPEN_NOFOLLOW)?;
+    conn.execute_batch(SCHEMA)?;
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT OR REPLACE INTO header (id, format, version, tool_version, fast_hash, base_path, created)
+            VALUES (0, ?1, ?2, ?3, ?4, ?5, ?6)",
+        params![header.format, header.version, header.tool_version, header.fast_hash,
+                path_to_sql(&header.base_path), time_to_sql(header.created)?],
+    )?;
+
+    let mut paths: HashSet<&[u8]> = HashSet::new();
+    {
+        // rows that are the same as before are left alone, so they don't fire the triggers
+        let mut upsert = tx.prepare(
+            "INSERT INTO entries (path, directory, fast_hash, size, modified, created, inode, device, changed, keep_separate, digest)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+                ON CONFLICT (path) DO UPDATE SET
+                    fast_hash = excluded.fast_hash, size = excluded.size, modified = excluded.modified,
+                    created = excluded.created, inode = excluded.inode, device = excluded.device,
+                    changed = excluded.changed, keep_separate = excluded.keep_separate, digest = excluded.digest
+                WHERE (fast_hash, size, modified, created, inode, device, changed, keep_separate, digest)
+                    IS NOT (excluded.fast_hash, excluded.size, excluded.modified, excluded.created, excluded.inode,
+                            excluded.device, excluded.changed, excluded.keep_separate, excluded.digest)")?;
+        for entry in entries {
+            let directory = entry.relative_path.parent().unwrap_or_else(|| Path::new(""));
+            upsert.execute(params![
+                path_to_sql(&entry.relative_path),
+                path_to_sql(directory),
+                entry.fast_hash.map(|hash| hash.to_string()),
+                entry.stat_size as i64,
+                time_to_sql(entry.stat_modified)?,
+                time_to_sql(entry.stat_created)?,
+                entry.stat_inode as i64,
+                entry.stat_device as i64,
+                time_to_sql(entry.stat_changed)?,
+                entry.keep_separate,
+                entry.digest,
+            ])?;
+            paths.insert(entry.relative_path.as_os_str().as_bytes());
+        }
+
+        let mut gone = vec![];
+        let mut stmt = tx.prepare("SELECT path FROM entries")?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let path = path_from_sql(row.get_raw(0));
+            if !paths.contains(path.as_os_str().as_bytes()) {
+                gone.push(path);
+            }
+        }
+        let mut delete = tx.prepare("DELETE FROM entries WHERE path = ?1")?;
+        for path in gone {
+            delete.execute(params![path_to_sql(&path)])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<FileEntry> {
+    let fast_hash = row.get::<_, Option<String>>(1)?
+        .map(|hash| hash.parse::<u128>()
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(1, Type::Text, Box::new(e))))
+        .transpose()?;
+    Ok(FileEntry {
+        relative_path: path_from_sql(row.get_raw(0)),
+        fast_hash,
+        stat_size: row.get::<_, i64>(2)? as u64,
+        stat_modified: time_from_sql(row.get(3)?),
+        stat_created: time_from_sql(row.get(4)?),
+        stat_inode: row.get::<_, i64>(5)? as u64,
+        stat_device: row.get::<_, i64>(6)? as u64,
+        stat_changed: time_from_sql(row.get(7)?),
+        keep_separate: row.get(8)?,
+        digest: row.get(9)?,
+    })
+}
+
+fn path_to_sql(path: &Path) -> Value {
+    match path.to_str() {
+        Some(s) => Value::Text(s.to_owned()),
+        None => Value::Blob(path.as_os_str().as_bytes().to_vec()),
+    }
+}
+
+fn path_from_sql(value: ValueRef) -> PathBuf {
+    match value {
+        ValueRef::Text(bytes) | ValueRef::Blob(bytes) => PathBuf::from(OsStr::from_bytes(bytes)),
+        _ => PathBuf::new(),
+    }
+}
+
+fn time_to_sql(time: SystemTime) -> Result<i64> {
+    let nanos = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => d.as_nanos() as i128,
+        Err(e) => -(e.duration().as_nanos() as i128),
+    };
+    if nanos > i64::MAX as i128 || nanos < i64::MIN as i128 {
+        return Err(format!("can't store {:?} in a sqlite index", time).into());
+    }
+    Ok(nanos as i64)
+}
+
+fn time_from_sql(nanos: i64) -> SystemTime {
+    let d = Duration::from_nanos(nanos.unsigned_abs());
+    if nanos < 0 {
+        SystemTime::UNIX_EPOCH - d
+    } else {
+        SystemTime::UNIX_EPOCH + d
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use rusqlite::{params, Connection};
+
+    use crate::lib::file_entry::FileEntry;
+    use crate::lib::files_index::IndexHeader;
+    use crate::lib::sqlite_index::{load, save};
+
+    fn entry(path: &[u8], inode: u64, fast_hash: Option<u128>) -> FileEntry {
+        FileEntry {
+            relative_path: PathBuf::from(OsStr::from_bytes(path)),
+            fast_hash,
+            stat_size: 4,
+            stat_modified: SystemTime::UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789),
+            stat_created: SystemTime::UNIX_EPOCH - Duration::from_secs(5),
+            stat_inode: inode,
+            stat_device: 2049,
+            stat_changed: SystemTime::UNIX_EPOCH,
+            keep_separate: false,
+            digest: None,
+        }
+    }
+
+    // (fast_hash, size, inodes, paths)
+    fn hash_groups(conn: &Connection) -> Vec<(String, i64, i64, i64)> {
+        let mut stmt = conn.prepare("SELECT fast_hash, size, inodes, paths FROM hash_groups ORDER BY fast_hash").unwrap();
+        let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("hardlink-deduplicator-sqlite-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("index.sqlite");
+        let header = IndexHeader {
+            format: "hardlink-deduplicator-index".to_owned(),
+            version: 2,
+            tool_version: "0.1.0".to_owned(),
+            fast_hash: "murmur3-x64-128".to_owned(),
+            base_path: dir.clone(),
+            created: SystemTime::UNIX_EPOCH + Duration::from_secs(7),
+        };
+        let mut entries = vec![
+            entry(b"a", 1, Some(10)),
+            entry(b"sub/b", 1, Some(10)),
+            entry(b"sub/caf\xe9", 2, Some(10)),
+            entry(b"unique", 3, None),
+        ];
+        entries[2].digest = Some("sha256:00".to_owned());
+        entries[3].keep_separate = true;
+
+        save(&db_path, &header, &entries).unwrap();
+        let (read_header, mut read_entries) = load(&db_path).unwrap();
+        assert_eq!(read_header, header);
+        read_entries.sort();
+        assert_eq!(read_entries, entries);
+        let conn = Connection::open(&db_path).unwrap();
+        assert_eq!(hash_groups(&conn), vec![("10".to_owned(), 4, 2, 3)]);
+        let paths: i64 = conn.query_row("SELECT paths FROM inodes WHERE inode = 1", params![], |row| row.get(0)).unwrap();
+        assert_eq!(paths, 2);
+        let in_sub: i64 = conn.query_row("SELECT count(*) FROM entries WHERE directory = 'sub'", params![], |row| row.get(0)).unwrap();
+        assert_eq!(in_sub, 2);
+
+        // the copy was linked to a, and unique got hashed
+        entries[2].stat_inode = 1;
+        entries[3].fast_hash = Some(20);
+        entries.remove(1);
+        save(&db_path, &header, &entries).unwrap();
+        let (_, mut read_entries) = load(&db_path).unwrap();
+        read_entries.sort();
+        assert_eq!(read_entries, entries);
+        assert_eq!(hash_groups(&conn), vec![("10".to_owned(), 4, 1, 2), ("20".to_owned(), 4, 1, 1)]);
+        let inodes: i64 = conn.query_row("SELECT count(*) FROM inodes", params![], |row| row.get(0)).unwrap();
+        assert_eq!(inodes, 2);
+
+        save(&db_path, &header, &[]).unwrap();
+        assert!(load(&db_path).unwrap().1.is_empty());
+        assert!(hash_groups(&conn).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::io::Read;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::fast_hash::hash_file;
+use super::file_entry::FileEntry;
+use super::files_index::{FilesIndex, VERIFY_CURSOR_FILE_NAME};
+use super::fs::AbstractFs;
+use super::Result;
+
+// how much work one verify run may do. At least one file is always verified, so a budget that is
+// smaller than a single file still makes progress.
+#[derive(Debug, Clone, Default)]
+pub struct Budget {
+    pub max_bytes: Option<u64>,
+    pub max_duration: Option<Duration>,
+}
+
+impl Budget {
+    fn exhausted(&self, bytes: u64, started: Instant) -> bool {
+        self.max_bytes.map_or(false, |max| bytes >= max)
+            || self.max_duration.map_or(false, |max| started.elapsed() >= max)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    // counted once per inode, hard links of a file are only read once
+    pub verified: usize,
+    pub bytes: u64,
+    // every path of a file whose contents don't match the stored hash
+    pub corrupt: Vec<PathBuf>,
+    // metadata changed since the file was indexed, so a different hash is expected
+    pub changed: Vec<PathBuf>,
+    pub unreadable: Vec<(PathBuf, String)>,
+    // files with a unique size never get hashed, so there is nothing to verify them against
+    pub unhashed: usize,
+    // the run got to the end of the index and started over from the beginning
+    pub wrapped: bool,
+    // the last path that was looked at; the next run starts after it
+    pub cursor: Option<PathBuf>,
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "verified {} files ({} bytes)", self.verified, self.bytes)?;
+        for path in &self.corrupt {
+            writeln!(f, "corrupt: {:?} does not match its stored hash", path)?;
+        }
+        for path in &self.changed {
+            writeln!(f, "skipped: {:?} changed since it was indexed", path)?;
+        }
+        for (path, e) in &self.unreadable {
+            writeln!(f, "skipped: {:?}: {}", path, e)?;
+        }
+        if self.unhashed > 0 {
+            writeln!(f, "{} files have no stored hash and can't be verified", self.unhashed)?;
+        }
+        if self.wrapped {
+            writeln!(f, "finished a full pass over the index")?;
+        }
+        Ok(())
+    }
+}
+
+// rehashes indexed files whose metadata still matches the index, starting after the cursor left by
+// the previous run and wrapping around at the end, until the budget runs out
+pub fn verify<Fs: AbstractFs>(fs: &Fs, index: &FilesIndex, budget: &Budget) -> Result<VerifyReport> {
+    let started = Instant::now();
+    let mut report = VerifyReport::default();
+
+    let mut entries: Vec<&FileEntry> = index.entries().iter()
+        .filter(|e| e.fast_hash.is_some())
+        .collect();
+    entries.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+    report.unhashed = index.len() - entries.len();
+    let mut paths_by_inode: HashMap<(u64, u64), Vec<&Path>> = HashMap::new();
+    for entry in &entries {
+        paths_by_inode.entry(entry.file_id()).or_default().push(&entry.relative_path);
+    }
+
+    let start = match read_cursor(fs, &index.base_path) {
+        Some(cursor) => entries.iter().take_while(|e| e.relative_path <= cursor).count(),
+        None => 0,
+    };
+    let mut seen = HashSet::new();
+    for (i, entry) in entries[start..].iter().chain(&entries[..start]).enumerate() {
+        if report.verified > 0 && budget.exhausted(report.bytes, started) {
+            break;
+        }
+        report.wrapped |= start + i == entries.len() && start > 0;
+        report.cursor = Some(entry.relative_path.clone());
+        if !seen.insert(entry.file_id()) {
+            continue;
+        }
+
+        // check the metadata before and after, if the file was written to in between the hash is
+        // meaningless
+        if !unchanged(fs, index, entry, &mut report) {
+            continue;
+        }
+        let hash = match hash_file(fs, &entry.absolute_path(&index.base_path)) {
+            Ok(hash) => hash,
+            Err(e) => {
+                report.unreadable.push((entry.relative_path.clone(), e.to_string()));
+                continue;
+            }
+        };
+        if !unchanged(fs, index, entry, &mut report) {
+            continue;
+        }
+        report.verified += 1;
+        report.bytes += entry.stat_size;
+        if Some(hash) != entry.fast_hash {
+            report.corrupt.extend(paths_by_inode[&entry.file_id()].iter().map(|p| p.to_path_buf()));
+        }
+    }
+    // going through everything in one run counts as a full pass too
+    report.wrapped |= start == 0 && seen.len() == paths_by_inode.len();
+    Ok(report)
+}
+
+// true if the entry still matches the disk, otherwise records why not
+fn unchanged<Fs: AbstractFs>(fs: &Fs, index: &FilesIndex, entry: &FileEntry, report: &mut VerifyReport) -> bool {
+    match entry.stat_again(fs, &index.base_path) {
+        Ok(on_disk) if entry.eq_except_hash(&on_disk) => true,
+        // a row from before we recorded the device and ctime, see FilesIndex::from_entries()
+        Ok(on_disk) if entry.missing_identity() && entry.with_identity_of(&on_disk).eq_except_hash(&on_disk) => true,
+        Ok(_) => {
+            report.changed.push(entry.relative_path.clone());
+            false
+        }
+        Err(e) => {
+            report.unreadable.push((entry.relative_path.clone(), e.to_string()));
+            false
+        }
+    }
+}
+
+// the raw bytes of the path, which don't have to be UTF-8
+fn read_cursor<Fs: AbstractFs>(fs: &Fs, base_path: &Path) -> Option<PathBuf> {
+    let mut contents = vec![];
+    fs.open(base_path.join(VERIFY_CURSOR_FILE_NAME)).ok()?.read_to_end(&mut contents).ok()?;
+    if contents.last() == Some(&b'\n') {
+        contents.pop();
+    }
+    Some(PathBuf::from(OsString::from_vec(contents)))
+}
+
+pub fn save_cursor<Fs: AbstractFs>(fs: &mut Fs, base_path: &Path, report: &VerifyReport) -> Result<()> {
+    match &report.cursor {
+        Some(cursor) => fs.write_to_file(base_path.join(VERIFY_CURSOR_FILE_NAME), &[cursor.as_os_str().as_bytes(), b"\n"].concat()),
+        None => Ok(()),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use crate::lib::files_index::FilesIndex;
+    use crate::lib::fs::TestFs;
+    use crate::lib::verify::{save_cursor, verify, Budget};
+
+    fn hashed_index(test_fs: &mut TestFs, files: &[(&str, &str)]) -> FilesIndex {
+        test_fs.set_cwd("/somefolder/");
+        let entries = files.iter()
+            .map(|(name, content)| {
+                let path = format!("/somefolder/{}", name);
+                let mut entry = test_fs.new_file_entry(&path, content);
+                entry.fast_hash = Some(crate::lib::fast_hash::hash_file(test_fs, Path::new(&path)).unwrap());
+                entry
+            })
+            .collect();
+        FilesIndex::from_checked_entries("/somefolder/", entries)
+    }
+
+    #[test]
+    fn test_verify_finds_corruption() {
+        let mut test_fs = TestFs::default();
+        let index = hashed_index(&mut test_fs, &[("test1", "asdf"), ("test2", "qwer"), ("test3", "zxcv")]);
+        // same size and timestamps, different contents
+        test_fs.corrupt("/somefolder/test2", b"qwez");
+        // this one was legitimately written to
+        test_fs.write_in_place("/somefolder/test3", b"zxcvbn");
+
+        let report = verify(&test_fs, &index, &Budget::default()).unwrap();
+        assert_eq!(report.verified, 2);
+        assert_eq!(report.corrupt, vec![PathBuf::from("test2")]);
+        assert_eq!(report.changed, vec![PathBuf::from("test3")]);
+        assert!(report.unreadable.is_empty());
+        assert!(report.wrapped);
+    }
+
+    #[test]
+    fn test_verify_rows_without_identity() {
+        let mut test_fs = TestFs::default();
+        let index = hashed_index(&mut test_fs, &[("test1", "asdf")]);
+        // a row from before the device and ctime were recorded
+        let mut entries = index.entries().to_vec();
+        entries[0].stat_device = 0;
+        entries[0].stat_changed = std::time::SystemTime::UNIX_EPOCH;
+        let index = FilesIndex::from_checked_entries("/somefolder/", entries);
+
+        let report = verify(&test_fs, &index, &Budget::default()).unwrap();
+        assert_eq!(report.verified, 1);
+        assert!(report.changed.is_empty());
+    }
+
+    #[test]
+    fn test_budget_and_cursor() {
+        let mut test_fs = TestFs::default();
+        let index = hashed_index(&mut test_fs, &[("test1", "aaaa"), ("test2", "bbbb"), ("test3", "cccc")]);
+        let budget = Budget { max_bytes: Some(8), max_duration: None };
+
+        let mut verified = vec![];
+        for _ in 0..3 {
+            let report = verify(&test_fs, &index, &budget).unwrap();
+            assert_eq!(report.bytes, 8);
+            verified.push((report.cursor.clone().unwrap(), report.wrapped));
+            save_cursor(&mut test_fs, Path::new("/somefolder/"), &report).unwrap();
+        }
+        // two files per run, starting where the last run stopped
+        assert_eq!(verified, vec![
+            (PathBuf::from("test2"), false),
+            (PathBuf::from("test1"), true),
+            (PathBuf::from("test3"), false),
+        ]);
+    }
+}
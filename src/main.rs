@@ -3,21 +3,41 @@ extern crate walkdir;
 
 use walkdir::WalkDir;
 
-use lib::fs::ReadOnlyFs;
-use lib::Result;
-use crate::lib::files_index::FilesIndex;
+use lib::fs::{GuardedFs, ReadOnlyFs};
+use lib::{Error, ErrorKind, Result, ResultExt};
+use crate::lib::{check, manifest, verify};
+use crate::lib::fast_hash::HashAlgorithm;
+use crate::lib::files_index::{self, FilesIndex, IndexFormat};
 use crate::lib::fs::{AbstractFs, RealFs};
-use std::path::Path;
+use crate::lib::lock::IndexLock;
+use crate::lib::reconcile::{ModeRule, MtimeRule, OwnerRule, Policy};
 
 mod lib;
 
 extern crate clap;
 
 use clap::Clap;
+use std::collections::HashSet;
 use std::ffi::OsStr;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0    success
+    1    other error
+    2    bad command line arguments
+    3    file not found
+    4    permission denied
+    5    cross-device link (the tree spans more than one filesystem)
+    6    a file changed while it was being scanned
+    7    the index is inconsistent
+    8    another run holds the lock on the folder (see --wait)
+    9    verify or check-manifest found files that don't match their hash
+    10   the index was written by a newer version of this tool";
 
 #[derive(Clap, Debug)]
-#[clap(version = "1.0", about = "deduplicates files")]
+#[clap(version = "1.0", about = "deduplicates files", after_help = EXIT_CODES_HELP)]
 struct Opts {
     /// folder to deduplicate files in
     folder: String,
@@ -30,54 +50,620 @@ struct Opts {
     /// if true, no filesystem changes will be made
     #[clap(short, long)]
     dry_run: bool,
+    /// if another run is working on the same folder, wait for it to finish instead of failing
+    #[clap(short, long)]
+    wait: bool,
+    /// give every path of a hard linked group that was edited in place its own copy again
+    #[clap(long)]
+    split_edited: bool,
+    /// append the index entries that went stale since the last run (deleted, modified, moved,
+    /// unreadable), and what --merged-mtime and --merged-mode changed, to this csv file
+    #[clap(long)]
+    changes_log: Option<String>,
+    /// also store each file's hash in a user.* xattr on the file, and reuse it when the size and
+    /// mtime still match, so hashes survive copies that don't take the index along. The owner of a
+    /// file can set both the xattr and the mtime, so a cached hash is only as trustworthy as the
+    /// file's owner: files are still compared byte by byte before they get linked, but a forged
+    /// hash can keep a file from being linked, or make verify report it as corrupt
+    #[clap(long)]
+    xattr_cache: bool,
+    /// folder with files that the folder's files can be linked to, like an older snapshot. Files in
+    /// it are never renamed, removed or replaced. Can be given more than once
+    #[clap(long)]
+    reference: Vec<String>,
+    /// glob pattern for files that are never renamed, removed or replaced by a link, like files
+    /// that are bind mounted somewhere. They can still be linked to. Relative patterns start at
+    /// the folder, and a pattern that matches a folder protects everything in it. Can be given
+    /// more than once
+    #[clap(long)]
+    protect: Vec<String>,
+    /// leave out files that were modified less than this many seconds ago, since they may still
+    /// be being written to. They are picked up by a later run
+    #[clap(long)]
+    min_age: Option<u64>,
+    /// set the modification time of a directory back to what it was after replacing a file in it
+    /// with a link
+    #[clap(long)]
+    preserve_dir_times: bool,
+    /// mtime of a file after another path is linked to it: keep (the linked-to file's), oldest
+    /// or newest of the two
+    #[clap(long, default_value = "keep")]
+    merged_mtime: MtimeRule,
+    /// mode of a file after another path is linked to it: keep (the linked-to file's),
+    /// most-restrictive or least-restrictive of the two. least-restrictive never adds setuid,
+    /// setgid, sticky or group and other write
+    #[clap(long, default_value = "keep")]
+    merged_mode: ModeRule,
+    /// owner of a file after another path is linked to it: keep (the linked-to file's), or
+    /// root-if-different, which gives files whose owners differ to root
+    #[clap(long, default_value = "keep")]
+    merged_owner: OwnerRule,
+    /// stay on the filesystem of the folder (and of each reference folder), like `find -xdev`.
+    /// This is the default
+    #[clap(long, overrides_with = "cross-mounts")]
+    one_file_system: bool,
+    /// also go into filesystems that are mounted inside the folder. Files are only ever linked to
+    /// files on the same filesystem
+    #[clap(long, overrides_with = "one-file-system")]
+    cross_mounts: bool,
+    /// go into symlinked folders and index symlinked files, as long as they point inside the
+    /// folder. Each file is indexed once, under its real path
+    #[clap(long)]
+    follow_symlinks: bool,
+    /// list the symlinks that point at a file with the same contents as other files. The
+    /// symlinks themselves are left as they are
+    #[clap(long)]
+    report_symlinks: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clap, Debug)]
+enum Command {
+    /// validate the index file against itself and the disk, without deduplicating anything
+    Check(CheckOpts),
+    /// rehash indexed files that haven't changed and report any that no longer match their hash
+    Verify(VerifyOpts),
+    /// write a sha256sum or b2sum compatible manifest of every indexed file
+    ExportManifest(ExportManifestOpts),
+    /// take the digests of indexed files from a manifest, so they don't have to be computed again
+    ImportManifest(ManifestOpts),
+    /// check the files in the folder against a manifest, like sha256sum -c
+    CheckManifest(ManifestOpts),
+    /// rewrite the index file as csv or binary. Later runs keep whichever format the index is in.
+    ConvertIndex(ConvertIndexOpts),
+}
+
+#[derive(Clap, Debug)]
+struct CheckOpts {
+    /// drop bad rows from the index, so the affected files get rehashed on the next run
+    #[clap(long)]
+    repair: bool,
+}
+
+#[derive(Clap, Debug)]
+struct VerifyOpts {
+    /// stop after reading about this many bytes; the next run carries on where this one stopped
+    #[clap(long)]
+    max_bytes: Option<u64>,
+    /// stop after about this many seconds; the next run carries on where this one stopped
+    #[clap(long)]
+    max_seconds: Option<u64>,
+}
+
+#[derive(Clap, Debug)]
+struct ExportManifestOpts {
+    /// sha256 or blake2b
+    #[clap(long, default_value = "sha256")]
+    algorithm: HashAlgorithm,
+    /// write the manifest here instead of to stdout
+    #[clap(short, long)]
+    output: Option<String>,
+}
+
+#[derive(Clap, Debug)]
+struct ManifestOpts {
+    /// manifest file, with paths relative to the folder
+    manifest: String,
+}
+
+#[derive(Clap, Debug)]
+struct ConvertIndexOpts {
+    /// csv, binary, which is smaller and faster to load for large trees, or sqlite, which can be
+    /// queried and is only partly rewritten on each run (needs the sqlite feature)
+    #[clap(long)]
+    to: IndexFormat,
 }
 
 
 fn main() {
     let opts: Opts = Opts::parse();
-    run(opts).unwrap();
+    let verbose = opts.verbose;
+    let quiet = opts.quiet;
+    if let Err(e) = run(opts) {
+        if !quiet {
+            eprintln!("error: {}", e);
+            if verbose {
+                if let Some(backtrace) = e.backtrace() {
+                    eprintln!("{:?}", backtrace);
+                }
+            }
+        }
+        std::process::exit(e.exit_code());
+    }
 }
 
 fn run(opts: Opts) -> Result<()> {
+    match &opts.command {
+        Some(Command::Check(check_opts)) => return if opts.dry_run {
+            run_check(&mut guarded(ReadOnlyFs {}, &opts)?, &opts, check_opts)
+        } else {
+            run_check(&mut guarded(RealFs::new(&opts.folder)?, &opts)?, &opts, check_opts)
+        },
+        Some(Command::Verify(verify_opts)) => return if opts.dry_run {
+            run_verify(&mut guarded(ReadOnlyFs {}, &opts)?, &opts, verify_opts)
+        } else {
+            run_verify(&mut guarded(RealFs::new(&opts.folder)?, &opts)?, &opts, verify_opts)
+        },
+        Some(Command::ExportManifest(export_opts)) => return if opts.dry_run {
+            run_export_manifest(&mut guarded(ReadOnlyFs {}, &opts)?, &opts, export_opts)
+        } else {
+            run_export_manifest(&mut guarded(RealFs::new(&opts.folder)?, &opts)?, &opts, export_opts)
+        },
+        Some(Command::ImportManifest(manifest_opts)) => return if opts.dry_run {
+            run_import_manifest(&mut guarded(ReadOnlyFs {}, &opts)?, &opts, manifest_opts)
+        } else {
+            run_import_manifest(&mut guarded(RealFs::new(&opts.folder)?, &opts)?, &opts, manifest_opts)
+        },
+        Some(Command::CheckManifest(manifest_opts)) => return run_check_manifest(&ReadOnlyFs {}, &opts, manifest_opts),
+        Some(Command::ConvertIndex(convert_opts)) => return if opts.dry_run {
+            run_convert_index(&mut guarded(ReadOnlyFs {}, &opts)?, &opts, convert_opts)
+        } else {
+            run_convert_index(&mut guarded(RealFs::new(&opts.folder)?, &opts)?, &opts, convert_opts)
+        },
+        None => (),
+    }
     if opts.dry_run {
         println!("running a dry run");
-        let mut fs = ReadOnlyFs {};
-        let files_index = run_for_folder(&mut fs, opts.folder)?;
+        let mut fs = guarded(ReadOnlyFs {}, &opts)?;
+        let mut files_index = run_for_folder(&mut fs, &opts)?;
+        // a binary index would be unreadable on a terminal
+        files_index.format = IndexFormat::Csv;
         files_index.save_to_writer(&mut std::io::stdout().lock())?;
+        check_consistency(&files_index, opts.quiet)?;
     } else {
-        let mut fs = RealFs {};
-        // let mut fs = ReadOnlyFs {};
-        let files_index = run_for_folder(&mut fs, opts.folder)?;
+        let mut fs = guarded(RealFs::new(&opts.folder)?, &opts)?;
+        let mut files_index = run_for_folder(&mut fs, &opts)?;
         files_index.save(&mut fs)?;
+        check_consistency(&files_index, opts.quiet)?;
     }
     Ok(())
 }
 
-fn run_for_folder<Fs: AbstractFs, P: AsRef<Path>>(fs: &mut Fs, path: P) -> Result<FilesIndex> {
-    let base_path = std::fs::canonicalize(path)?;
-    let mut files_index = FilesIndex::for_base_path(fs, &base_path)?;
-    files_index.sanity_check();
+// every change to a path that matches --protect fails, whichever code path tries it
+fn guarded<Fs: AbstractFs>(fs: Fs, opts: &Opts) -> Result<GuardedFs<Fs>> {
+    let base_path = fs.canonicalize(&opts.folder)?;
+    let patterns = opts.protect.iter()
+        .map(|pattern| {
+            let pattern = if pattern.starts_with('/') {
+                pattern.clone()
+            } else {
+                format!("{}/{}", glob::Pattern::escape(&base_path.to_string_lossy()), pattern)
+            };
+            glob::Pattern::new(&pattern).map_err(|e| Error::from(format!("bad --protect pattern {:?}: {}", pattern, e)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(GuardedFs::new(fs, patterns))
+}
+
+fn run_for_folder<Fs: AbstractFs>(fs: &mut Fs, opts: &Opts) -> Result<FilesIndex> {
+    let base_path = fs.canonicalize(&opts.folder)?;
+    let reference_roots = opts.reference.iter()
+        .map(|root| fs.canonicalize(root))
+        .collect::<Result<Vec<PathBuf>>>()?;
+    for root in &reference_roots {
+        // the files would be both in the folder and in the reference folder
+        if root.starts_with(&base_path) || base_path.starts_with(root) {
+            return Err(format!("reference folder {:?} overlaps with {:?}", root, base_path).into());
+        }
+    }
+    let mut files_index = FilesIndex::for_base_path(fs, &base_path, opts.wait)?;
+    warn_fallback(&files_index.fallback);
+    files_index.config.xattr_cache = opts.xattr_cache;
+    files_index.config.settle_cutoff = opts.min_age.map(|secs| SystemTime::now() - Duration::from_secs(secs));
+    files_index.config.preserve_dir_times = opts.preserve_dir_times;
+    files_index.config.reconcile = Policy { mtime: opts.merged_mtime, mode: opts.merged_mode, owner: opts.merged_owner };
+    files_index.retain_references(&reference_roots);
+    handle_edited_groups(fs, &mut files_index, opts)?;
+
+    // reference files go in first, so the files in the folder get linked straight to them
+    let mut unsettled = 0;
+    for root in &reference_roots {
+        walk_files(root, opts, &mut vec![], |path| {
+            if files_index.is_held_back(path) {
+                return;
+            }
+            if let Err(e) = files_index.add_reference_file(fs, path) {
+                report_add_error(path, &e, opts, &mut unsettled);
+            }
+        });
+    }
+    let mut symlinks = vec![];
+    walk_files(&base_path, opts, &mut symlinks, |path| {
+        if let Ok(relative_path) = path.strip_prefix(&base_path) {
+            if files_index.is_held_back(relative_path) {
+                return;
+            }
+        }
+        if let Err(e) = files_index.add_file(fs, path) {
+            report_add_error(path, &e, opts, &mut unsettled);
+        }
+    });
+    for (path, e) in files_index.retry_queued(fs) {
+        report_add_error(&path, &e, opts, &mut unsettled);
+    }
+    if !opts.quiet {
+        if let (Some(min_age), true) = (opts.min_age, unsettled > 0) {
+            println!("left out {} files that were modified in the last {} seconds", unsettled, min_age);
+        }
+        if !files_index.unstable.is_empty() {
+            println!("{} files changed while they were being hashed, they will be tried again on the next run",
+                     files_index.unstable.len());
+        }
+    }
+    report_stale(fs, &files_index, opts)?;
+    report_reconciled(fs, &files_index, opts)?;
+    if opts.report_symlinks {
+        report_symlinks(fs, &files_index, &symlinks);
+    }
+    Ok(files_index)
+}
+
+// files that are still being written to are expected with --min-age, so those are only counted
+fn report_add_error(path: &Path, e: &Error, opts: &Opts, unsettled: &mut usize) {
+    if e.kind() == ErrorKind::Unsettled {
+        *unsettled += 1;
+        if opts.verbose {
+            println!("{}", e);
+        }
+        return;
+    }
+    eprintln!("{}: {}", path.display(), e);
+}
 
-    WalkDir::new(&base_path)
+// calls f for every regular file under root, except the ones that belong to the index or are left
+// over from an interrupted run. With --follow-symlinks, f gets the real path of every file, once,
+// and files whose real path is outside of root are skipped. Symlinks to files end up in symlinks.
+// Symlinks to directories outside of root aren't followed at all, since they can lead anywhere,
+// even to /.
+fn walk_files<F: FnMut(&Path)>(root: &Path, opts: &Opts, symlinks: &mut Vec<PathBuf>, mut f: F) {
+    let mut seen = HashSet::new();
+    WalkDir::new(root)
+        .same_file_system(opts.one_file_system || !opts.cross_mounts)
+        .follow_links(opts.follow_symlinks)
         .into_iter()
+        .filter_entry(|e| {
+            !(e.depth() > 0 && e.path_is_symlink() && e.file_type().is_dir())
+                || matches!(std::fs::canonicalize(e.path()), Ok(real_path) if real_path.starts_with(root))
+        })
         .for_each(|r| {
             match r {
-                Ok(f) if f.file_type().is_file() => {
-                    if f.path().file_name() == Some(OsStr::new(".index_file.csv")) {
+                Ok(e) if e.file_type().is_file() => {
+                    if files_index::is_index_file(e.path()) {
                         return;
                     }
-                    if f.path().extension() == Some(OsStr::new(".backup")) {
+                    if e.path().extension() == Some(OsStr::new(".backup")) {
                         return;
                     }
-                    if let Err(e) = files_index.add_file(fs, f.path()) {
-                        println!("{}: {:?}", f.path().display(), e);
+                    if e.path_is_symlink() {
+                        symlinks.push(e.path().to_owned());
+                    }
+                    if !opts.follow_symlinks {
+                        f(e.path());
+                        return;
+                    }
+                    if let Ok(real_path) = std::fs::canonicalize(e.path()) {
+                        if real_path.starts_with(root) && !files_index::is_index_file(&real_path) && seen.insert(real_path.clone()) {
+                            f(&real_path);
+                        }
                     }
                 }
-                // directory or symlink, we don't care
+                Ok(e) if e.file_type().is_symlink() => symlinks.push(e.path().to_owned()),
+                // directory, we don't care
                 Ok(_) => (),
-                Err(_) => (),
+                Err(e) => {
+                    if let (Some(path), true) = (e.path(), e.loop_ancestor().is_some() && opts.verbose) {
+                        println!("not following symlink loop at {}", path.display());
+                    }
+                }
             }
         });
-    files_index.sanity_check();
-    Ok(files_index)
+}
+
+// the symlinks stay as they are, but if what they point at has copies, they could point at any
+// of them
+fn report_symlinks<Fs: AbstractFs>(fs: &Fs, files_index: &FilesIndex, symlinks: &[PathBuf]) {
+    for link in symlinks {
+        // dangling links have nothing to report
+        let target = match fs.canonicalize(link) {
+            Ok(target) => target,
+            Err(_) => continue,
+        };
+        // reference files are indexed by their absolute path
+        let target = target.strip_prefix(&files_index.base_path).map(Path::to_path_buf).unwrap_or(target);
+        let duplicates: Vec<String> = files_index.duplicates_of(&target).iter()
+            .map(|e| e.relative_path.display().to_string())
+            .collect();
+        if !duplicates.is_empty() {
+            let link = link.strip_prefix(&files_index.base_path).unwrap_or(link);
+            println!("symlink {} -> {} has the same contents as {}", link.display(), target.display(), duplicates.join(", "));
+        }
+    }
+}
+
+// moves are only known after the walk, so this has to wait until then
+fn report_stale<Fs: AbstractFs>(fs: &mut Fs, files_index: &FilesIndex, opts: &Opts) -> Result<()> {
+    if files_index.stale.is_empty() {
+        return Ok(());
+    }
+    if !opts.quiet {
+        println!("{}", files_index.stale.summary());
+        if opts.verbose {
+            for stale in files_index.stale.iter() {
+                println!("\t{}: {}", stale.entry.relative_path.display(), stale.change);
+            }
+        }
+    }
+    if let Some(log_path) = &opts.changes_log {
+        if opts.dry_run {
+            println!("not writing the changes log in a dry run");
+        } else {
+            files_index.stale.append_to_log(fs, log_path, &files_index.base_path, SystemTime::now())?;
+        }
+    }
+    Ok(())
+}
+
+fn report_reconciled<Fs: AbstractFs>(fs: &mut Fs, files_index: &FilesIndex, opts: &Opts) -> Result<()> {
+    if files_index.reconciled.is_empty() {
+        return Ok(());
+    }
+    if !opts.quiet {
+        println!("{}", files_index.reconciled.summary());
+        if opts.verbose {
+            for reconciled in files_index.reconciled.iter() {
+                println!("\t{}: {} {}", reconciled.relative_path.display(), reconciled.change.name(), reconciled.change);
+            }
+        }
+    }
+    if let Some(log_path) = &opts.changes_log {
+        // nothing gets linked in a dry run, so there is nothing to log either
+        files_index.reconciled.append_to_log(fs, log_path, &files_index.base_path, SystemTime::now())?;
+    }
+    Ok(())
+}
+
+fn handle_edited_groups<Fs: AbstractFs>(fs: &mut Fs, files_index: &mut FilesIndex, opts: &Opts) -> Result<()> {
+    let edited_groups = std::mem::take(&mut files_index.edited_groups);
+    for group in &edited_groups {
+        if !opts.quiet {
+            println!("hard linked files were edited in place ({} -> {} bytes), so all of these changed:",
+                     group.old_size, group.new_size);
+            for path in &group.relative_paths {
+                println!("\t{}", path.display());
+            }
+        }
+        if opts.split_edited {
+            for entry in files_index.split_edited_group(fs, group)? {
+                if !opts.quiet {
+                    println!("split {} into its own copy", entry.relative_path.display());
+                }
+            }
+        }
+    }
+    if !edited_groups.is_empty() && !opts.split_edited && !opts.quiet {
+        println!("these paths are left out of this run; run with --split-edited to give each of them its own copy again");
+    }
+    Ok(())
+}
+
+fn run_check<Fs: AbstractFs>(fs: &mut Fs, opts: &Opts, check_opts: &CheckOpts) -> Result<()> {
+    let base_path = fs.canonicalize(&opts.folder)?;
+    // only a repair writes anything, but it shouldn't race with a normal run
+    let lock = if check_opts.repair {
+        IndexLock::acquire(fs, &base_path, opts.wait)?
+    } else {
+        None
+    };
+    let loaded = FilesIndex::load(fs, &base_path)?;
+    warn_fallback(&loaded.fallback);
+    let report = check::check_entries(fs, &base_path, &loaded.entries);
+    if !opts.quiet {
+        print!("{}", report);
+    }
+    if report.is_clean() {
+        return match lock {
+            Some(lock) => lock.release(fs),
+            None => Ok(()),
+        };
+    }
+    if !check_opts.repair {
+        return Err(Error::invariant(format!("index for {:?} needs repair", base_path)));
+    }
+
+    let mut files_index = check::repair(&base_path, &loaded, &report);
+    if opts.dry_run {
+        files_index.format = IndexFormat::Csv;
+        files_index.save_to_writer(&mut std::io::stdout().lock())?;
+    } else {
+        files_index.save(fs)?;
+    }
+    if let Some(lock) = lock {
+        lock.release(fs)?;
+    }
+    if !opts.quiet {
+        println!("repaired index, dropped {} rows", loaded.entries.len() - files_index.len());
+    }
+    check_consistency(&files_index, opts.quiet)
+}
+
+fn run_verify<Fs: AbstractFs>(fs: &mut Fs, opts: &Opts, verify_opts: &VerifyOpts) -> Result<()> {
+    let base_path = fs.canonicalize(&opts.folder)?;
+    // doesn't change the index, but files being relinked by another run would look corrupt
+    let lock = IndexLock::acquire(fs, &base_path, opts.wait)?;
+    // the rows as they were saved: verify() stats only the files it gets to within the budget,
+    // and reports the ones that changed since
+    let loaded = FilesIndex::load(fs, &base_path)?;
+    warn_fallback(&loaded.fallback);
+    let files_index = FilesIndex::from_checked_entries(&base_path, loaded.entries);
+    let budget = verify::Budget {
+        max_bytes: verify_opts.max_bytes,
+        max_duration: verify_opts.max_seconds.map(Duration::from_secs),
+    };
+    let report = verify::verify(fs, &files_index, &budget)?;
+    if !opts.quiet {
+        print!("{}", report);
+    }
+    if !opts.dry_run {
+        verify::save_cursor(fs, &base_path, &report)?;
+    }
+    if let Some(lock) = lock {
+        lock.release(fs)?;
+    }
+    if !report.corrupt.is_empty() {
+        return Err(Error::corrupt(format!("{} files don't match their stored hash", report.corrupt.len())));
+    }
+    Ok(())
+}
+
+fn run_export_manifest<Fs: AbstractFs>(fs: &mut Fs, opts: &Opts, export_opts: &ExportManifestOpts) -> Result<()> {
+    let base_path = fs.canonicalize(&opts.folder)?;
+    let mut files_index = FilesIndex::for_base_path(fs, &base_path, opts.wait)?;
+    warn_fallback(&files_index.fallback);
+    let export = manifest::export(fs, &mut files_index, export_opts.algorithm);
+    match &export_opts.output {
+        Some(output) if !opts.dry_run => fs.write_to_file(output, export.manifest.to_string().as_bytes())?,
+        _ => print!("{}", export.manifest),
+    }
+    for (path, e) in &export.unreadable {
+        eprintln!("warning: left {:?} out of the manifest: {}", path, e);
+    }
+    // keep the digests, so the next export doesn't have to read everything again
+    if opts.dry_run {
+        files_index.release_lock(fs)?;
+    } else {
+        files_index.save(fs)?;
+    }
+    if !export.unreadable.is_empty() {
+        return Err(Error::from(format!("{} files couldn't be read and are not in the manifest", export.unreadable.len())));
+    }
+    Ok(())
+}
+
+fn run_import_manifest<Fs: AbstractFs>(fs: &mut Fs, opts: &Opts, manifest_opts: &ManifestOpts) -> Result<()> {
+    let base_path = fs.canonicalize(&opts.folder)?;
+    let manifest = read_manifest(fs, &manifest_opts.manifest)?;
+    let mut files_index = FilesIndex::for_base_path(fs, &base_path, opts.wait)?;
+    warn_fallback(&files_index.fallback);
+    let report = manifest::import(fs, &mut files_index, &manifest);
+    if !opts.quiet {
+        print!("{}", report);
+    }
+    if opts.dry_run {
+        files_index.release_lock(fs)
+    } else {
+        files_index.save(fs)
+    }
+}
+
+fn run_check_manifest<Fs: AbstractFs>(fs: &Fs, opts: &Opts, manifest_opts: &ManifestOpts) -> Result<()> {
+    let base_path = fs.canonicalize(&opts.folder)?;
+    let manifest = read_manifest(fs, &manifest_opts.manifest)?;
+    let report = manifest::check(fs, &base_path, &manifest);
+    if !opts.quiet {
+        print!("{}", report);
+    }
+    if !report.is_clean() {
+        return Err(Error::corrupt(format!("{} files don't match the manifest",
+                                          report.mismatched.len() + report.unreadable.len())));
+    }
+    Ok(())
+}
+
+fn run_convert_index<Fs: AbstractFs>(fs: &mut Fs, opts: &Opts, convert_opts: &ConvertIndexOpts) -> Result<()> {
+    let base_path = fs.canonicalize(&opts.folder)?;
+    if opts.dry_run {
+        let loaded = FilesIndex::load(fs, &base_path)?;
+        warn_fallback(&loaded.fallback);
+        println!("would convert {} rows to {}", loaded.entries.len(), convert_opts.to);
+        return Ok(());
+    }
+    let (rows, fallback) = FilesIndex::convert(fs, &base_path, convert_opts.to, opts.wait)?;
+    warn_fallback(&fallback);
+    if !opts.quiet {
+        println!("converted {} rows to {}", rows, convert_opts.to);
+    }
+    Ok(())
+}
+
+// the index file was unreadable and the previous one was loaded instead, see LoadedIndex
+fn warn_fallback(fallback: &Option<String>) {
+    if let Some(fallback) = fallback {
+        eprintln!("warning: {}", fallback);
+    }
+}
+
+fn read_manifest<Fs: AbstractFs>(fs: &Fs, path: &str) -> Result<manifest::Manifest> {
+    let mut text = vec![];
+    fs.open(path)?.read_to_end(&mut text).context("read", path)?;
+    manifest::Manifest::parse(&text)
+}
+
+// the index is saved even if it is inconsistent, because the maps get rebuilt from the entries when
+// it is loaded again; we still want a non-zero exit code so someone notices
+fn check_consistency(files_index: &FilesIndex, quiet: bool) -> Result<()> {
+    let violations = files_index.sanity_check();
+    if violations.is_empty() {
+        return Ok(());
+    }
+    if !quiet {
+        for violation in &violations {
+            eprintln!("index inconsistency: {}", violation);
+        }
+    }
+    Err(Error::invariant(format!("found {} index inconsistencies", violations.len())))
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use clap::Clap;
+
+    use crate::{walk_files, Opts};
+
+    #[test]
+    fn test_walk_files_stays_inside_root() {
+        let dir = std::env::temp_dir().join(format!("hardlink-deduplicator-walk-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (root, outside) = (dir.join("root"), dir.join("outside"));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(root.join("sub/a"), "asdf").unwrap();
+        std::fs::write(outside.join("b"), "asdf").unwrap();
+        std::os::unix::fs::symlink(outside.join("b"), outside.join("link_to_b")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("out")).unwrap();
+        std::os::unix::fs::symlink(root.join("sub"), root.join("also_sub")).unwrap();
+        let root = root.canonicalize().unwrap();
+
+        let opts = Opts::parse_from(vec!["hardlink-deduplicator", "--follow-symlinks", root.to_str().unwrap()]);
+        let mut symlinks = vec![];
+        let mut files = vec![];
+        walk_files(&root, &opts, &mut symlinks, |path| files.push(path.to_owned()));
+        // nothing behind out is looked at, not even its symlinks, but links inside root are fine
+        assert_eq!(files, vec![root.join("sub/a")]);
+        assert_eq!(symlinks, Vec::<PathBuf>::new());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }